@@ -1,22 +1,33 @@
 use std::{
+  collections::HashSet,
   num::NonZero,
   path::{Path, PathBuf},
+  sync::Mutex,
 };
 
 use anyhow::{Context, Result};
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
 use derive_more::Display;
 
 use crate::{
   cache::Cache,
   channel::{Receiver, Sender},
   config::{Config, Language},
-  ext::{IntoExt, OptionExt},
+  etags::Etags,
+  ext::{IntoExt, Leak, PathExt},
+  filter::{Filter, Matcher},
+  format::Format,
+  symbol::{Kind, KindFilter},
+  tags::Tags,
+  walker::WalkOptions,
 };
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
+  #[command(subcommand)]
+  command: Option<Command>,
+
   /// A toml file with language queries and symbols.
   ///
   /// The default configuration will be applied if this argument is not
@@ -24,15 +35,20 @@ pub struct Args {
   #[arg(short, long = "config")]
   config_path: Option<PathBuf>,
 
-  /// The file or directory to search for symbols in.
+  /// The files or directories to search for symbols in.
   ///
   /// If this is a directory, it is recursively searched for files with
   /// supported extensions.
   ///
-  /// If this is a file, it is searched for symbols, and the `--language` flag
-  /// is ignored, and the language appropriate for the file is used.
-  #[arg(default_value = ".")]
-  search_path: PathBuf,
+  /// If this is a file, it is searched for symbols, and the language
+  /// appropriate for the file is used.
+  ///
+  /// This can be repeated to search several files or directories with the
+  /// same workers and cache, in which case the project root of the cache is
+  /// that of the first, and `lsp` only falls back to the first. Paths under
+  /// another search path are skipped.
+  #[arg(value_name = "SEARCH_PATH", default_value = ".")]
+  search_paths: Vec<PathBuf>,
 
   /// The characters between properties of a single symbol.
   ///
@@ -57,27 +73,214 @@ pub struct Args {
   #[arg(long)]
   separator0: bool,
 
-  /// Only show symbols from files with extensions matching this language.
+  /// Only show symbols from files with extensions matching these languages.
+  ///
+  /// This can be repeated, or given a comma-separated list of languages.
+  /// Symbols are shown from the languages of both this and `--extension`.
+  #[arg(long = "language", value_name = "LANGUAGE", value_delimiter = ',')]
+  languages: Vec<Language>,
+
+  /// Only show symbols from files with the languages matching these
+  /// extensions.
+  ///
+  /// This can be repeated, or given a comma-separated list of extensions.
+  /// Symbols are shown from the languages of both this and `--language`.
+  #[arg(long = "extension", value_name = "EXTENSION", value_delimiter = ',')]
+  extensions: Vec<String>,
+
+  /// Only show symbols of these kinds.
+  ///
+  /// This can be repeated, or given a comma-separated list of kinds.
+  #[arg(long = "kind", value_name = "KIND", value_delimiter = ',', conflicts_with_all = ["tags_path", "etags_path"])]
+  kinds: Vec<Kind>,
+
+  /// Don't show symbols of these kinds.
   ///
-  /// This flag takes precedence over the `--extension` flag.
+  /// This can be repeated, or given a comma-separated list of kinds. Files
+  /// parsed while either this or `--kind` is provided are not cached.
+  #[arg(
+    long = "exclude-kind",
+    value_name = "KIND",
+    value_delimiter = ',',
+    conflicts_with_all = ["tags_path", "etags_path"]
+  )]
+  excluded_kinds: Vec<Kind>,
+
+  /// Read the files to list from this file, one path per line, or from stdin
+  /// if this is `-`, instead of walking the search path.
+  ///
+  /// Paths are relative to the current directory, and their languages are
+  /// detected as when walking. Listed files that don't exist or aren't in a
+  /// supported language are skipped, and no files are ignored. This is only
+  /// used when listing symbols.
+  #[arg(long, value_name = "PATH", conflicts_with_all = ["watch", "from_cache"])]
+  files_from: Option<PathBuf>,
+
+  /// Separate the paths of `--files-from` by null bytes instead of newlines,
+  /// such as the output of `git ls-files -z`.
+  #[arg(long, requires = "files_from")]
+  files_from0: bool,
+
+  /// Walk hidden files and directories.
   #[arg(long)]
-  language: Option<Language>,
+  hidden: bool,
 
-  /// Only show symbols from files with the language matching this extension.
+  /// Don't respect ignore files, which are `.gitignore`, `.ignore`, and
+  /// `.cymbalignore` files, along with git's global and repository excludes.
+  #[arg(long)]
+  no_ignore: bool,
+
+  /// Don't respect git's ignore files, which are `.gitignore` files along with
+  /// git's global and repository excludes.
   ///
-  /// The `--language` flag takes precedence over this flag.
+  /// `.ignore` and `.cymbalignore` files are still respected.
+  #[arg(long)]
+  no_ignore_vcs: bool,
+
+  /// Follow symbolic links.
   #[arg(long)]
-  extension: Option<String>,
+  follow: bool,
+
+  /// Don't walk files more than this many directories below the search path.
+  ///
+  /// A depth of 1 only walks the files directly in the search path.
+  #[arg(long, value_name = "DEPTH")]
+  max_depth: Option<usize>,
+
+  /// Only walk files matching these globs, which use `.gitignore` syntax and
+  /// are relative to the search path.
+  ///
+  /// This can be repeated. Globs prefixed with `!` exclude files instead, and
+  /// the last matching glob takes precedence. Files matching a glob are walked
+  /// even if they are hidden or ignored.
+  #[arg(long = "glob", value_name = "GLOB")]
+  globs: Vec<String>,
+
+  /// Don't walk files or directories matching these globs, which use
+  /// `.gitignore` syntax and are relative to the search path, such as
+  /// `vendor/` or `*_test.go`.
+  ///
+  /// This can be repeated, and takes precedence over `--glob`.
+  #[arg(long = "exclude", value_name = "GLOB")]
+  excludes: Vec<String>,
 
   /// Directory to cache parsed symbols.
   ///
   /// Files are reparsed if their cached mtime differs from than their current
-  /// mtime, or the path of the file doesn't exist in the cache. This option
+  /// mtime, the path of the file doesn't exist in the cache, or the queries or
+  /// grammar of their language or the version of cymbal changed. This option
   /// is typically used when `symbols` is called from the same directory
   /// multiple times, such as searching over a code base in an editor.
-  #[arg(long = "cache")]
+  ///
+  /// Files are cached by their path relative to the project root, which is
+  /// the nearest directory containing `.git`, or otherwise the directory
+  /// containing the cache, so the cache is shared between runs from any
+  /// directory of the project and survives moving the project.
+  ///
+  /// A cache can be shared by multiple processes running at once, such as an
+  /// editor and a git hook, in which case the last write of a file wins, and
+  /// files written since a run started walking are not deleted by it.
+  ///
+  /// If this is `auto`, the cache is kept in a directory per project under
+  /// `$XDG_CACHE_HOME/cymbal`, or `~/.cache/cymbal` if `XDG_CACHE_HOME` isn't
+  /// set, where the project is the nearest ancestor of the search path
+  /// containing `.git`, otherwise the nearest ancestor that already has a
+  /// cache, and otherwise the search path. Use `cache list` and `cache prune`
+  /// to manage these caches.
+  #[arg(long = "cache", env = "CYMBAL_CACHE")]
   cache_dirpath: Option<PathBuf>,
 
+  /// Reuse cached symbols of files whose mtime changed but whose content did
+  /// not, such as after switching git branches, by comparing hashes of their
+  /// content.
+  ///
+  /// Files identical to another cached file also reuse its symbols.
+  #[arg(long, requires = "cache_dirpath")]
+  validate_content: bool,
+
+  /// Write the symbols cached under the search path without walking it or
+  /// reading any files.
+  ///
+  /// Symbols are read with a single query, so this is much faster than a
+  /// walk over a large search path, but symbols of files that changed since
+  /// they were last cached are stale, and files never cached are missing. Use
+  /// this when the cache is known to be fresh, such as while `--watch` or
+  /// `serve` is running with the same cache.
+  #[arg(long, requires = "cache_dirpath", conflicts_with_all = ["tags_path", "etags_path", "watch", "validate_content"])]
+  from_cache: bool,
+
+  /// Write the end of each symbol and the range of its definition after its
+  /// trailing text in the `text` output format.
+  ///
+  /// These are the end line and column of the symbol, followed by the start
+  /// line, start column, end line, and end column of its definition, which are
+  /// empty if its query has no `@definition` capture. Ranges are always
+  /// included in the `json` and `jsonl` formats.
+  #[arg(long)]
+  ranges: bool,
+
+  /// The format symbols are written in.
+  ///
+  /// The `json` and `jsonl` formats ignore the `--delimiter`, `--separator`,
+  /// and `--color` flags.
+  #[arg(short, long, default_value_t = Output::Text)]
+  output: Output,
+
+  /// Write a sorted, universal-ctags compatible `tags` file to this path
+  /// instead of writing symbols to stdout.
+  ///
+  /// If `--cache` is also provided and the `tags` file already exists, only
+  /// the entries for files whose mtime has changed are rewritten, unless the
+  /// queries, grammars, or version of cymbal changed, in which case every
+  /// entry is.
+  #[arg(long = "tags", conflicts_with = "output")]
+  tags_path: Option<PathBuf>,
+
+  /// Write an Emacs `TAGS` file to this path instead of writing symbols to
+  /// stdout.
+  ///
+  /// File names are relative to the directory of the `TAGS` file, as Emacs
+  /// resolves them, or absolute for files outside of it.
+  #[arg(long = "etags", conflicts_with_all = ["output", "tags_path"])]
+  etags_path: Option<PathBuf>,
+
+  /// Keep running after symbols are listed, writing a JSON line to stdout for
+  /// every symbol added, removed, or moved as files under the search path
+  /// change.
+  ///
+  /// Each line has an `event` of `added`, `removed`, or `moved`, along with
+  /// the fields of the symbol as in `--output=jsonl`. Moved symbols also have
+  /// their previous position under `from`.
+  #[arg(long, conflicts_with_all = ["tags_path", "etags_path"])]
+  watch: bool,
+
+  /// Only write symbols whose content matches this regex.
+  #[arg(long = "match", value_name = "REGEX", conflicts_with_all = ["fuzzy", "tags_path", "etags_path", "watch"])]
+  regex: Option<String>,
+
+  /// Only write symbols whose content fuzzily matches this query, from best to
+  /// worst match.
+  ///
+  /// Unless `--ignore-case` is set, the query is case-sensitive only if it
+  /// contains uppercase characters.
+  #[arg(long, value_name = "QUERY", conflicts_with_all = ["tags_path", "etags_path", "watch"])]
+  fuzzy: Option<String>,
+
+  /// Match `--match` and `--fuzzy` case-insensitively.
+  #[arg(short, long)]
+  ignore_case: bool,
+
+  /// Match `--match` and `--fuzzy` against the leading and trailing text of
+  /// symbols along with their content.
+  #[arg(long)]
+  match_context: bool,
+
+  /// Write at most this many symbols.
+  ///
+  /// With `--fuzzy`, these are the best matches.
+  #[arg(long, conflicts_with_all = ["tags_path", "etags_path", "watch"])]
+  limit: Option<usize>,
+
   /// Whether to emit ANSI color escape sequences.
   ///
   /// If the `NO_COLOR` environment variable is set, no ANSI color escape
@@ -97,18 +300,84 @@ pub struct Args {
 }
 
 impl Args {
+  /// The `--cache` value for a cache in the user's cache directory.
+  const AUTO_CACHE: &'static str = "auto";
+
+  pub fn command(&self) -> Option<&Command> {
+    self.command.as_ref()
+  }
+
+  pub fn watch(&self) -> bool {
+    self.watch
+  }
+
+  pub fn is_from_cache(&self) -> bool {
+    self.from_cache
+  }
+
+  pub fn limit(&self) -> Option<usize> {
+    self.limit
+  }
+
+  pub fn match_context(&self) -> bool {
+    self.match_context
+  }
+
+  /// The first search path, which determines the project root of the cache.
   pub fn search_path(&self) -> &Path {
-    &self.search_path
+    // clap requires at least one search path, as it has a default
+    &self.search_paths[0]
   }
 
-  pub async fn cache(&self) -> Result<Option<Cache>> {
+  /// The search paths that aren't under another search path, whose files
+  /// would otherwise be listed twice.
+  pub fn search_paths(&self) -> Vec<PathBuf> {
+    let normalized: Vec<PathBuf> = self.search_paths.iter().map(PathExt::normalized).collect();
+
     self
-      .cache_dirpath
-      .as_deref()
-      .map(Cache::from_dirpath)
-      .into_future()
-      .await
-      .transpose()
+      .search_paths
+      .iter()
+      .enumerate()
+      .filter(|(i, _)| {
+        // of identical search paths, only the first is kept
+        !normalized
+          .iter()
+          .enumerate()
+          .any(|(j, other)| normalized[*i].starts_with(other) && (normalized[*i] != *other || j < *i))
+      })
+      .map(|(_, search_path)| search_path.clone())
+      .collect()
+  }
+
+  /// The cache at `--cache`, if any, for parsing files into it.
+  pub async fn cache(&self) -> Result<Option<Cache>> {
+    let Some(cache) = self.read_cache().await? else { return None.ok() };
+    cache.set_root().await?;
+
+    cache.some().ok()
+  }
+
+  /// The cache at `--cache`, if any, for only reading the symbols in it, such
+  /// as with `--from-cache` or `search`.
+  pub async fn read_cache(&self) -> Result<Option<Cache>> {
+    match self.cache_dirpath.as_deref() {
+      None => None.ok(),
+      Some(cache_dirpath) if cache_dirpath == Self::AUTO_CACHE => Cache::auto(self.search_path(), self.validate_content).await?.some().ok(),
+      Some(cache_dirpath) => Cache::from_dirpath(cache_dirpath, self.search_path(), self.validate_content)
+        .await?
+        .some()
+        .ok(),
+    }
+  }
+
+  /// The existing cache at `--cache`, for the `cache` command.
+  pub async fn existing_cache(&self) -> Result<Cache> {
+    let cache_dirpath = self.cache_dirpath.as_deref().context("the cache command requires --cache")?;
+    if cache_dirpath == Self::AUTO_CACHE {
+      return Cache::open(&Cache::project_dir_path(self.search_path())?).await;
+    }
+
+    Cache::open(cache_dirpath).await
   }
 
   pub async fn config(&self) -> Result<Config> {
@@ -118,10 +387,45 @@ impl Args {
       Config::default()
     };
 
-    if let Some(language) = self.language() {
-      config.for_language(language).ok()
+    let languages = self.languages()?;
+    let config = if languages.is_empty() {
+      config
     } else {
-      config.ok()
+      config.for_languages(&languages)
+    };
+
+    match self.kind_filter() {
+      Some(kinds) => config.for_kinds(kinds).ok(),
+      None => config.ok(),
+    }
+  }
+
+  /// The kinds selected by `--kind` and `--exclude-kind`, if either is
+  /// provided.
+  pub fn kind_filter(&self) -> Option<KindFilter> {
+    if self.kinds.is_empty() && self.excluded_kinds.is_empty() {
+      return None;
+    }
+
+    KindFilter::new(self.kinds.iter().copied().collect(), self.excluded_kinds.iter().copied().collect()).some()
+  }
+
+  /// The file listing the files to list, and the byte separating their paths.
+  pub fn files_from(&self) -> Option<(&Path, u8)> {
+    let separator = if self.files_from0 { b'\0' } else { b'\n' };
+
+    self.files_from.as_deref().map(|files_from| (files_from, separator))
+  }
+
+  pub fn walk_options(&self) -> WalkOptions {
+    WalkOptions {
+      hidden: self.hidden,
+      no_ignore: self.no_ignore,
+      no_ignore_vcs: self.no_ignore_vcs,
+      follow_links: self.follow,
+      max_depth: self.max_depth,
+      globs: self.globs.clone(),
+      excludes: self.excludes.clone(),
     }
   }
 
@@ -148,6 +452,51 @@ impl Args {
     if self.separator0 { '\0' } else { self.separator }
   }
 
+  pub async fn format(&self) -> Result<Format> {
+    if let Some(tags_path) = &self.tags_path {
+      let fingerprint = self.config().await?.languages_fingerprint();
+      let tags = Tags::new(tags_path.clone(), self.cache_dirpath.is_some(), fingerprint).await?;
+
+      return Format::Ctags { tags: tags.leak() }.ok();
+    }
+
+    if let Some(etags_path) = &self.etags_path {
+      return Format::Etags {
+        etags: Etags::new(etags_path.clone()).leak(),
+      }
+      .ok();
+    }
+
+    let format = match self.output {
+      Output::Text => Format::Text {
+        delimiter: self.delimiter(),
+        separator: self.separator(),
+        color: self.color(),
+        ranges: self.ranges,
+      },
+      Output::Json => Format::Json {
+        is_first: Mutex::new(true).leak(),
+      },
+      Output::Jsonl => Format::JsonLines,
+    };
+
+    format.ok()
+  }
+
+  pub fn filter(&self) -> Result<Option<Filter>> {
+    let matcher = match (&self.regex, &self.fuzzy) {
+      (Some(regex), _) => Matcher::regex(regex, self.ignore_case)?.some(),
+      (None, Some(query)) => Matcher::fuzzy(query, self.ignore_case).some(),
+      (None, None) => None,
+    };
+
+    if matcher.is_none() && self.limit.is_none() {
+      return None.ok();
+    }
+
+    Filter::new(matcher, self.match_context, self.limit).some().ok()
+  }
+
   pub fn color(&self) -> bool {
     match self.color {
       Color::Never => false,
@@ -157,15 +506,115 @@ impl Args {
   }
 
   /// Whether additional restrictions on the set of walked files are present.
+  ///
+  /// Selecting languages does not restrict the walked files, only which of
+  /// them are parsed, so every cached file is still walked. Similarly,
+  /// `--hidden` and `--no-ignore` only add to the walked files.
   pub fn is_filtering(&self) -> bool {
-    self.search_path.is_file() || self.language().is_some()
+    self.files_from.is_some() || self.max_depth.is_some() || !self.globs.is_empty() || !self.excludes.is_empty()
   }
 
-  fn language(&self) -> Option<Language> {
-    self.language.or(self.extension.as_deref().and_then(Language::from_extension))
+  /// The languages selected by `--language` and `--extension`, which are
+  /// empty if neither is provided.
+  pub fn languages(&self) -> Result<HashSet<Language>> {
+    let mut languages: HashSet<Language> = self.languages.iter().copied().collect();
+    for extension in &self.extensions {
+      let language = Language::from_extension(extension).with_context(|| format!("no language for extension: {extension}"))?;
+      languages.insert(language);
+    }
+
+    languages.ok()
   }
 }
 
+#[derive(Subcommand)]
+pub enum Command {
+  /// Run a language server over stdio.
+  ///
+  /// The server answers `workspace/symbol` requests for every file under the
+  /// workspace root (or the first `SEARCH_PATH` if the client provides no
+  /// root), and `textDocument/documentSymbol` requests, including for unsaved
+  /// buffers.
+  Lsp,
+  /// Run a daemon that keeps symbols indexed and answers queries over a Unix
+  /// socket.
+  ///
+  /// Files under `SEARCH_PATH` are watched and reparsed in the background as
+  /// they change. Each connection to the socket sends a single line, and
+  /// receives every symbol containing that line (ignoring case) in the output
+  /// format. An empty line matches every symbol.
+  Serve {
+    /// The path of the Unix socket to listen on.
+    #[arg(long, value_name = "SOCKET_PATH")]
+    socket: PathBuf,
+  },
+  /// Search the full-text index of the cache at `--cache` for symbols whose
+  /// content contains `TERM`, or whose camelCase or snake_case segments start
+  /// with those of `TERM`.
+  ///
+  /// Only files under `SEARCH_PATH` that were cached by a previous listing,
+  /// `--watch`, or `serve` are searched, without walking or reading any files.
+  /// Symbols are written from best to worst match: exact matches, then
+  /// prefixes, then segments, then substrings, ignoring case. At most
+  /// `--limit` symbols are written, or 100 if it isn't provided. With
+  /// `--match-context`, the leading and trailing text of symbols is also
+  /// searched.
+  Search {
+    /// The text to search for.
+    term: String,
+  },
+  /// Inspect and maintain the cache at `--cache`, or the caches of every
+  /// project with `--cache=auto`.
+  Cache {
+    #[command(subcommand)]
+    command: CacheCommand,
+  },
+}
+
+#[derive(Subcommand)]
+pub enum CacheCommand {
+  /// Show the number of cached files and symbols per language and kind, and
+  /// the size of the cache on disk.
+  Stats,
+  /// Delete cached files that no longer exist.
+  ///
+  /// Unlike the cleanup done when listing symbols, this considers every cached
+  /// file, not only those under `SEARCH_PATH`.
+  Gc,
+  /// Delete every cached file and symbol.
+  Clear,
+  /// Reparse a random sample of cached files and compare their symbols against
+  /// the cached symbols.
+  ///
+  /// Files modified since they were cached, or whose queries changed, are
+  /// reported as stale. This fails if the symbols of any other file differ.
+  /// Use the same `--config` that the cache was written with.
+  Verify {
+    /// The number of files to reparse.
+    ///
+    /// Set to 0 to reparse every cached file.
+    #[arg(long, default_value_t = 100)]
+    sample: usize,
+  },
+  /// Rebuild the cache to reclaim unused space.
+  Vacuum,
+  /// List the caches of every project created by `--cache=auto`, from most to
+  /// least recently used.
+  ///
+  /// This ignores `--cache`.
+  List,
+  /// Delete the caches created by `--cache=auto` of projects that no longer
+  /// exist or that haven't been used recently.
+  ///
+  /// This ignores `--cache`.
+  Prune {
+    /// Also delete the caches of projects that haven't been used in this many
+    /// days.
+    #[arg(long, value_name = "DAYS", default_value_t = 30)]
+    older_than: u32,
+  },
+}
+
 #[derive(Copy, Clone, Display, ValueEnum)]
 #[display(rename_all = "lowercase")]
 pub enum Color {
@@ -173,3 +622,41 @@ pub enum Color {
   Always,
   Auto,
 }
+
+#[derive(Copy, Clone, Display, ValueEnum)]
+#[display(rename_all = "lowercase")]
+pub enum Output {
+  /// Delimited text, one symbol per separator.
+  Text,
+  /// A single JSON array of symbols.
+  Json,
+  /// One JSON object per symbol, each on its own line.
+  Jsonl,
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn search_paths(search_paths: &[&str]) -> Vec<PathBuf> {
+    Args::try_parse_from(["cymbal"].iter().chain(search_paths)).unwrap().search_paths()
+  }
+
+  #[test]
+  fn nested_and_duplicate_search_paths_are_skipped() {
+    assert_eq!(search_paths(&[]), [Path::new(".")]);
+    assert_eq!(search_paths(&["src", "src/cache", "tests"]), [Path::new("src"), Path::new("tests")]);
+    assert_eq!(search_paths(&["src/cache", "src"]), [Path::new("src")]);
+
+    // of the same directory, only the first spelling is kept
+    assert_eq!(search_paths(&["./src", "src", "src/"]), [Path::new("./src")]);
+    assert_eq!(search_paths(&["src", "tests/../src/cache"]), [Path::new("src")]);
+
+    // a sibling of the current directory isn't under it
+    assert_eq!(search_paths(&[".", "../shared", "src"]), [Path::new("."), Path::new("../shared")]);
+    // but the current directory is under its parent
+    assert_eq!(search_paths(&[".", ".."]), [Path::new("..")]);
+    // and a sibling with a common prefix is not nested
+    assert_eq!(search_paths(&["src", "src2"]), [Path::new("src"), Path::new("src2")]);
+  }
+}
@@ -1,120 +1,397 @@
 use std::{
-  collections::HashSet,
+  collections::{HashMap, HashSet},
   path::{Path, PathBuf},
+  sync::{Arc, Mutex},
+  time::Duration,
 };
 
 use anyhow::{Context, Result};
 use chrono::{DateTime, Utc};
 use futures::{Stream, StreamExt};
+use sha2::{Digest, Sha256};
 use sqlx::{
-  Either, QueryBuilder, Sqlite, SqlitePool,
+  Connection, Either, QueryBuilder, Sqlite, SqliteConnection, SqliteExecutor, SqlitePool,
   sqlite::{SqliteConnectOptions, SqliteJournalMode, SqliteSynchronous},
 };
+use tokio::sync::{OnceCell, mpsc, oneshot};
+use xxhash_rust::xxh3::xxh3_64;
 
+use self::writer::{Message, Writer};
 use crate::{
-  ext::{Ignore, IntoExt, PathExt},
-  symbol::{FileInfo, Symbol},
+  config::Language,
+  ext::{Ignore, IntoExt, PathBufExt, PathExt},
+  search::SearchTerm,
+  symbol::{FileInfo, Kind, KindFilter, Symbol},
   utils::RawPath,
 };
 
+mod writer;
+
 #[derive(Clone)]
 pub struct Cache {
   pool: SqlitePool,
+  /// The directory that cached file paths are relative to, so that the cache
+  /// can be shared between working directories and machines.
+  root: Arc<Path>,
+  /// The info of every cached file by key, loaded with a single query the
+  /// first time it is needed, and then kept in sync with the file table.
+  files: Files,
+  /// Changes to the cache, which are committed in batches by a single task.
+  writer: mpsc::Sender<Message>,
+  /// Whether files whose mtime changed are checked for changes to their
+  /// content before being reparsed.
+  validates_content: bool,
 }
 
 impl Cache {
   const CACHE_FILE_NAME: &'static str = "cymbal-cache.sqlite";
+  const LOCK_FILE_NAME: &'static str = "cymbal-cache.lock";
+  /// How long to wait for another process sharing the cache to finish
+  /// writing before failing.
+  const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
 
-  pub async fn from_dirpath(cache_dir_path: &Path) -> Result<Self> {
+  /// Opens the cache in `cache_dir_path`, creating it if it doesn't exist.
+  ///
+  /// Files are cached by their path relative to the root of the project
+  /// containing `search_path`, see [`Cache::detect_root`].
+  ///
+  /// If `validates_content` is set, files whose mtime changed are only
+  /// reparsed if their content also changed, and files whose content is
+  /// identical to that of another cached file reuse its symbols.
+  pub async fn from_dirpath(cache_dir_path: &Path, search_path: &Path, validates_content: bool) -> Result<Self> {
     if !cache_dir_path.exists() {
       tokio::fs::create_dir_all(cache_dir_path).await?;
     }
 
-    let cache_filepath = cache_dir_path.join(Self::CACHE_FILE_NAME);
-    let options = SqliteConnectOptions::new()
-      .filename(cache_filepath)
-      .create_if_missing(true)
-      .journal_mode(SqliteJournalMode::Wal)
-      .synchronous(SqliteSynchronous::Normal);
+    let pool = Self::connect(cache_dir_path, Self::options(cache_dir_path).create_if_missing(true)).await?;
+    let root = Self::detect_root(&pool, cache_dir_path, search_path).await?;
 
-    Self::from_options(options).await
+    Self::new(pool, root, validates_content).await
   }
 
-  pub async fn is_file_cached(&self, file_path: &Path, file_modified: &DateTime<Utc>) -> Result<bool> {
-    // TODO(enricozb): try writing an EXISTS query to check performance
-    let Some(cache_file_info) = self.get_file_info(file_path).await? else { return false.ok() };
-    let is_cached = &cache_file_info.modified == file_modified && cache_file_info.is_fully_parsed;
+  /// Opens the cache of the project containing `search_path` in the user's
+  /// cache directory, creating it if it doesn't exist, see
+  /// [`Cache::project_dir_path`].
+  pub async fn auto(search_path: &Path, validates_content: bool) -> Result<Self> {
+    let root = Self::project_root(search_path)?;
+    let cache_dir_path = Self::projects_dir_path()?.join(Self::project_hash(&root));
+    tokio::fs::create_dir_all(&cache_dir_path)
+      .await
+      .with_context(|| format!("failed to create cache directory {}", cache_dir_path.display()))?;
+
+    let pool = Self::connect(&cache_dir_path, Self::options(&cache_dir_path).create_if_missing(true)).await?;
 
-    is_cached.ok()
+    Self::new(pool, root, validates_content).await
   }
 
-  async fn get_file_info(&self, file_path: &Path) -> Result<Option<FileInfo>> {
-    sqlx::query_as("SELECT modified, is_fully_parsed FROM file WHERE file.path = $1")
-      .bind(file_path.as_bytes())
-      .fetch_optional(&self.pool)
+  /// Opens the existing cache in `cache_dir_path` with the root it was last
+  /// used with, for inspecting and maintaining the cache rather than parsing.
+  pub async fn open(cache_dir_path: &Path) -> Result<Self> {
+    let pool = Self::connect(cache_dir_path, Self::options(cache_dir_path))
       .await
-      .context("failed to get file info")
+      .with_context(|| format!("failed to open cache in {}", cache_dir_path.display()))?;
+    let root = match Self::stored_root(&pool).await? {
+      Some(stored_root) => stored_root,
+      None => {
+        let cache_dir_path = cache_dir_path.canonicalize().context("failed to canonicalize cache directory")?;
+
+        cache_dir_path.parent().unwrap_or(&cache_dir_path).to_path_buf()
+      }
+    };
+
+    Self::new(pool, root, false).await
   }
 
-  pub async fn insert_file(&self, file_path: &Path, file_modified: &DateTime<Utc>) -> Result<()> {
-    sqlx::query(
-      "
-        INSERT INTO file (path, modified)
-          VALUES ($1, $2)
-        ON CONFLICT DO UPDATE SET
-          modified = excluded.modified,
-          is_fully_parsed = FALSE
-      ",
-    )
-    .bind(file_path.as_bytes())
-    .bind(file_modified)
-    .execute(&self.pool)
-    .await
-    .map(Ignore::ignore)
-    .context("failed to insert file info")
-  }
-
-  async fn insert_symbols_impl(&self, file_path_bytes: &[u8], symbols: &[Symbol]) -> Result<()> {
-    let mut query = QueryBuilder::new("INSERT INTO symbol (file_path, kind, language, line, column, content, leading, trailing)");
-    query.push_values(symbols, |mut query, symbol| {
-      query.push_bind(file_path_bytes);
-      query.push_bind(symbol.kind);
-      query.push_bind(symbol.language);
-      query.push_bind(symbol.line);
-      query.push_bind(symbol.column);
-      query.push_bind(&symbol.content);
-      query.push_bind(&symbol.leading);
-      query.push_bind(&symbol.trailing);
-    });
+  /// Spawns the writer task of the cache.
+  async fn new(pool: SqlitePool, root: PathBuf, validates_content: bool) -> Result<Self> {
+    let files = Files::default();
 
-    query.build().execute(&self.pool).await.context("failed to insert symbols")?;
+    Self {
+      writer: Writer::spawn(pool.clone(), files.clone()),
+      pool,
+      root: root.into(),
+      files,
+      validates_content,
+    }
+    .ok()
+  }
 
-    ().ok()
+  fn options(cache_dir_path: &Path) -> SqliteConnectOptions {
+    SqliteConnectOptions::new()
+      .filename(cache_dir_path.join(Self::CACHE_FILE_NAME))
+      .journal_mode(SqliteJournalMode::Wal)
+      .synchronous(SqliteSynchronous::Normal)
+      .busy_timeout(Self::BUSY_TIMEOUT)
   }
 
-  pub async fn insert_symbols(&self, file_path: &Path, symbols: &[Symbol]) -> Result<()> {
-    let file_path_bytes = file_path.as_bytes();
+  /// The root of the project containing `search_path`.
+  ///
+  /// This is the nearest ancestor of `search_path` containing a `.git`
+  /// directory, otherwise the root stored in the cache if it contains
+  /// `search_path`, and otherwise the directory containing the cache.
+  async fn detect_root(pool: &SqlitePool, cache_dir_path: &Path, search_path: &Path) -> Result<PathBuf> {
+    let search_path = search_path.canonicalize().context("failed to canonicalize search path")?;
+    if let Some(root) = Self::git_root(&search_path) {
+      return root.to_path_buf().ok();
+    }
 
-    sqlx::query("DELETE FROM symbol WHERE file_path = $1")
-      .bind(file_path_bytes)
-      .execute(&self.pool)
+    if let Some(stored_root) = Self::stored_root(pool).await?
+      && search_path.starts_with(&stored_root)
+    {
+      return stored_root.ok();
+    }
+
+    let cache_dir_path = cache_dir_path.canonicalize().context("failed to canonicalize cache directory")?;
+
+    cache_dir_path.parent().unwrap_or(&cache_dir_path).to_path_buf().ok()
+  }
+
+  /// The root the cache was last used with, if any.
+  async fn stored_root(executor: impl SqliteExecutor<'_>) -> Result<Option<PathBuf>> {
+    let stored_root: Option<RawPath> = sqlx::query_scalar("SELECT value FROM metadata WHERE key = 'root'")
+      .fetch_optional(executor)
       .await
-      .context("failed to delete stale symbols")?;
+      .context("failed to get root")?;
+
+    stored_root.map(PathBuf::from).ok()
+  }
+
+  fn git_root(search_path: &Path) -> Option<&Path> {
+    search_path.ancestors().find(|ancestor| ancestor.join(".git").exists())
+  }
 
-    for symbols_chunk in symbols.chunks(100) {
-      self.insert_symbols_impl(file_path_bytes, symbols_chunk).await?;
+  /// The root of the project containing `search_path` for a cache in the
+  /// user's cache directory.
+  ///
+  /// This is the nearest ancestor of `search_path` containing a `.git`
+  /// directory, otherwise the nearest ancestor that already has a cache, and
+  /// otherwise the directory searched.
+  fn project_root(search_path: &Path) -> Result<PathBuf> {
+    let search_path = search_path.canonicalize().context("failed to canonicalize search path")?;
+    if let Some(root) = Self::git_root(&search_path) {
+      return root.to_path_buf().ok();
     }
 
-    ().ok()
+    let projects_dir_path = Self::projects_dir_path()?;
+    if let Some(root) = search_path
+      .ancestors()
+      .find(|ancestor| projects_dir_path.join(Self::project_hash(ancestor)).exists())
+    {
+      return root.to_path_buf().ok();
+    }
+
+    if search_path.is_file()
+      && let Some(parent) = search_path.parent()
+    {
+      return parent.to_path_buf().ok();
+    }
+
+    search_path.ok()
+  }
+
+  /// The directory containing the caches of every project opened with
+  /// [`Cache::auto`], which is `$XDG_CACHE_HOME/cymbal`, or
+  /// `~/.cache/cymbal` if `XDG_CACHE_HOME` isn't set.
+  pub fn projects_dir_path() -> Result<PathBuf> {
+    // relative paths are invalid and ignored, per the XDG base directory spec
+    let cache_home = match std::env::var_os("XDG_CACHE_HOME").map(PathBuf::from) {
+      Some(cache_home) if cache_home.is_absolute() => cache_home,
+      _ => std::env::var_os("HOME")
+        .map(PathBuf::from)
+        .context("neither XDG_CACHE_HOME nor HOME is set")?
+        .join(".cache"),
+    };
+
+    cache_home.join("cymbal").ok()
+  }
+
+  /// The directory of the cache of the project containing `search_path`
+  /// under [`Cache::projects_dir_path`], which is named after a hash of the
+  /// project root.
+  pub fn project_dir_path(search_path: &Path) -> Result<PathBuf> {
+    let root = Self::project_root(search_path)?;
+
+    Self::projects_dir_path()?.join(Self::project_hash(&root)).ok()
   }
 
-  pub async fn set_file_is_fully_parsed(&self, file_path: &Path) -> Result<()> {
-    sqlx::query("UPDATE file SET is_fully_parsed = TRUE WHERE path = $1")
-      .bind(file_path.as_bytes())
+  fn project_hash(root: &Path) -> String {
+    let hash = format!("{:x}", Sha256::digest(root.as_bytes()));
+
+    hash[..16].to_string()
+  }
+
+  /// Stores the root of the cache, which later runs from within it reuse, see
+  /// [`Cache::detect_root`]. This is only done by runs that write to the
+  /// cache, so that merely reading it changes nothing.
+  pub async fn set_root(&self) -> Result<()> {
+    sqlx::query("INSERT INTO metadata (key, value) VALUES ('root', $1) ON CONFLICT DO UPDATE SET value = excluded.value")
+      .bind(self.root.as_ref().as_bytes())
       .execute(&self.pool)
       .await
       .map(Ignore::ignore)
-      .context("failed to set is_fully_parsed for file info")
+      .context("failed to set root")
+  }
+
+  /// The key of `file_path` in the cache, which is its path relative to the
+  /// root, or its absolute path if it is outside of the root.
+  ///
+  /// The root is canonical, so a path that is only under it once symlinks are
+  /// resolved, such as one in a symlinked checkout, is resolved before being
+  /// considered outside of it.
+  fn key(&self, file_path: &Path) -> PathBuf {
+    let file_path = file_path.normalized();
+    if let Ok(relative_path) = file_path.strip_prefix(&self.root) {
+      return relative_path.to_path_buf();
+    }
+
+    // a deleted file can't be resolved, but its directory usually can
+    let canonical_path = file_path.canonicalize().ok().or_else(|| {
+      let parent = file_path.parent()?.canonicalize().ok()?;
+
+      parent.join(file_path.file_name()?).some()
+    });
+
+    match canonical_path
+      .as_deref()
+      .map(|canonical_path| canonical_path.strip_prefix(&self.root))
+    {
+      Some(Ok(relative_path)) => relative_path.to_path_buf(),
+      _ => file_path,
+    }
+  }
+
+  /// Whether `key` is under `search_key`, where the keys of files outside of
+  /// the root are absolute, and so are never under a relative search key,
+  /// even the root's empty one.
+  fn is_under(key: &Path, search_key: &Path) -> bool {
+    key.starts_with(search_key) && (search_key.is_absolute() || key.is_relative())
+  }
+
+  /// Whether the cached symbols of `file_path` are up to date, that is, the
+  /// file has not been modified and its language's queries have the same
+  /// `fingerprint` since it was parsed.
+  ///
+  /// If the cache validates content, a modified file is also up to date if
+  /// its content is unchanged, or if it is identical to that of another cached
+  /// file, whose symbols are copied, but can only be read once the copy is
+  /// flushed. Otherwise, the content read to find out is returned to be
+  /// parsed.
+  pub async fn lookup_file(&self, file_path: &Path, file_modified: &DateTime<Utc>, fingerprint: &str) -> Result<Lookup> {
+    let key = self.key(file_path);
+    let file_info = self
+      .get_file_info(&key)
+      .await?
+      .filter(|file_info| file_info.is_fully_parsed && file_info.fingerprint == fingerprint);
+
+    if let Some(file_info) = &file_info
+      && &file_info.modified == file_modified
+    {
+      return Lookup::Hit.ok();
+    }
+
+    if !self.validates_content {
+      return Lookup::Miss(None).ok();
+    }
+
+    let content = Content::read(file_path).await?;
+    if let Some(file_info) = &file_info
+      && file_info.content_hash == content.hash.some()
+    {
+      self.set_file_modified(&key, file_modified).await?;
+
+      return Lookup::Hit.ok();
+    }
+
+    if self.copy_identical_file(&key, file_modified, fingerprint, content.hash).await? {
+      return Lookup::Copied.ok();
+    }
+
+    Lookup::Miss(content.some()).ok()
+  }
+
+  /// The hash of a file's content, which is stable across versions of cymbal.
+  #[allow(clippy::cast_possible_wrap)]
+  pub fn content_hash(content_bytes: &[u8]) -> i64 {
+    // sqlite integers are signed
+    xxh3_64(content_bytes) as i64
+  }
+
+  async fn get_file_info(&self, key: &Path) -> Result<Option<FileInfo>> {
+    let files = self.files.get_or_load(&self.pool).await?.lock().expect("poisoned files lock");
+
+    files.get(key).cloned().ok()
+  }
+
+  /// Caches `file_path` and its `symbols`, replacing any previous ones.
+  ///
+  /// The file is written by the writer task along with other pending files,
+  /// so it may not be cached until [`Cache::flush`] returns.
+  pub async fn write_file(
+    &self,
+    file_path: &Path,
+    file_modified: &DateTime<Utc>,
+    fingerprint: &str,
+    content_hash: i64,
+    symbols: Vec<Symbol>,
+  ) -> Result<()> {
+    self
+      .send(Message::Write {
+        key: self.key(file_path),
+        modified: *file_modified,
+        fingerprint: fingerprint.to_string(),
+        content_hash,
+        symbols,
+      })
+      .await
+  }
+
+  async fn set_file_modified(&self, key: &Path, file_modified: &DateTime<Utc>) -> Result<()> {
+    self
+      .send(Message::SetModified {
+        key: key.to_path_buf(),
+        modified: *file_modified,
+      })
+      .await
+  }
+
+  /// Caches `key` with the symbols of another fully parsed file with the same
+  /// `fingerprint` and `content_hash`, returning whether one existed.
+  ///
+  /// The copy is committed with the writer's next batch, so the symbols of
+  /// `key` can only be read after [`Cache::flush`] returns.
+  async fn copy_identical_file(&self, key: &Path, file_modified: &DateTime<Utc>, fingerprint: &str, content_hash: i64) -> Result<bool> {
+    let identical_key = {
+      let files = self.files.get_or_load(&self.pool).await?.lock().expect("poisoned files lock");
+
+      files.identical_key(key, fingerprint, content_hash).map(Path::to_path_buf)
+    };
+
+    let Some(identical_key) = identical_key else { return false.ok() };
+
+    self
+      .send(Message::Copy {
+        key: key.to_path_buf(),
+        identical_key,
+        modified: *file_modified,
+        fingerprint: fingerprint.to_string(),
+        content_hash,
+      })
+      .await?;
+
+    true.ok()
+  }
+
+  /// Waits for every pending write to be committed, returning the first error
+  /// since the last flush, if any.
+  pub async fn flush(&self) -> Result<()> {
+    let (sender, receiver) = oneshot::channel();
+    self.send(Message::Flush(sender)).await?;
+
+    receiver.await.context("cache writer stopped")?
+  }
+
+  async fn send(&self, message: Message) -> Result<()> {
+    self.writer.send(message).await.ok().context("cache writer stopped")
   }
 
   #[allow(deprecated)]
@@ -123,60 +400,730 @@ impl Cache {
     'a: 'path,
   {
     sqlx::query_as("SELECT * FROM symbol WHERE symbol.file_path = $1")
-      .bind(file_path.as_bytes())
+      .bind(self.key(file_path).into_bytes())
       .fetch_many(&self.pool)
       .filter_map(async |row| row.map(Either::right).transpose())
   }
 
-  pub async fn delete_stale_file_paths(&self, file_paths: &HashSet<PathBuf>) -> Result<()> {
-    let cached_file_paths = self.get_file_paths();
-    futures::pin_mut!(cached_file_paths);
+  /// Every symbol of the fully parsed files cached under any of
+  /// `search_paths`, in one of `languages` (or any language if empty) and of a
+  /// kind contained by `kinds`, ordered by file and then position.
+  ///
+  /// Each symbol is paired with the path of its file as if `search_paths` had
+  /// been walked. Nothing is read from the filesystem, so the symbols may be
+  /// stale.
+  pub fn symbols_under<'a>(
+    &'a self,
+    search_paths: &[PathBuf],
+    languages: &HashSet<Language>,
+    kinds: Option<&KindFilter>,
+  ) -> impl Stream<Item = Result<(PathBuf, Symbol)>> + 'a {
+    let (languages, included_kinds, excluded_kinds) = Self::symbol_filters(languages, kinds);
+    let search_keys = self.search_keys(search_paths);
 
-    while let Some(file_path) = cached_file_paths.next().await {
-      if !file_paths.contains(&file_path) {
-        self.delete_file(&file_path).await?;
-      }
+    sqlx::query_as::<_, CachedSymbol>(
+      "
+        SELECT symbol.* FROM symbol
+          JOIN file ON file.path = symbol.file_path
+          WHERE file.is_fully_parsed
+            AND ($1 IS NULL OR symbol.language IN (SELECT value FROM json_each($1)))
+            AND ($2 IS NULL OR symbol.kind IN (SELECT value FROM json_each($2)))
+            AND ($3 IS NULL OR symbol.kind NOT IN (SELECT value FROM json_each($3)))
+          ORDER BY symbol.file_path, symbol.offset
+      ",
+    )
+    .bind(languages)
+    .bind(included_kinds)
+    .bind(excluded_kinds)
+    .fetch(&self.pool)
+    .filter_map(move |row| Self::walked_symbol(&search_keys, row).ready())
+  }
+
+  /// The cached symbols under any of `search_paths` matching `term`, in one of
+  /// `languages` (or any language if empty) and of a kind contained by
+  /// `kinds`, from best to worst match.
+  ///
+  /// Symbols whose content is the term are first, then those starting with
+  /// it, then those whose segments start with those of the term, and then
+  /// those containing it, or whose leading or trailing text contains it if
+  /// `context` is set. Ties are broken by the length of the content, and then
+  /// by position. Terms too short for the full-text index only match by
+  /// prefix and segments.
+  pub fn search<'a>(
+    &'a self,
+    search_paths: &[PathBuf],
+    term: &SearchTerm,
+    context: bool,
+    languages: &HashSet<Language>,
+    kinds: Option<&KindFilter>,
+    limit: usize,
+  ) -> impl Stream<Item = Result<(PathBuf, Symbol)>> + 'a {
+    macro_rules! search_query {
+      ($condition:literal) => {
+        concat!(
+          "
+            SELECT symbol.* FROM symbol
+              JOIN file ON file.path = symbol.file_path
+              WHERE ",
+          $condition,
+          "
+                AND file.is_fully_parsed
+                AND ($5 IS NULL OR symbol.language IN (SELECT value FROM json_each($5)))
+                AND ($6 IS NULL OR symbol.kind IN (SELECT value FROM json_each($6)))
+                AND ($7 IS NULL OR symbol.kind NOT IN (SELECT value FROM json_each($7)))
+                AND EXISTS (
+                  SELECT 1 FROM json_each($8) AS search_key
+                    WHERE (length(search_key.value) = 0 AND substr(symbol.file_path, 1, 1) != X'2F')
+                      OR symbol.file_path = unhex(search_key.value)
+                      OR substr(symbol.file_path, 1, length(search_key.value) / 2 + 1) = unhex(search_key.value || '2f')
+                )
+              ORDER BY
+                CASE
+                  WHEN symbol.content = $2 COLLATE NOCASE THEN 0
+                  WHEN symbol.content LIKE $3 ESCAPE '\\' THEN 1
+                  WHEN symbol.segments LIKE $4 ESCAPE '\\' THEN 2
+                  ELSE 3
+                END,
+                length(symbol.content), symbol.file_path, symbol.offset
+              LIMIT $9
+          "
+        )
+      };
     }
 
-    ().ok()
+    let fts_query = term.fts_query(context);
+    let query = if fts_query.is_some() {
+      search_query!("symbol.rowid IN (SELECT rowid FROM symbol_search WHERE symbol_search MATCH $1)")
+    } else {
+      search_query!("$1 IS NULL AND (symbol.content LIKE $3 ESCAPE '\\' OR symbol.segments LIKE $4 ESCAPE '\\')")
+    };
+    let (languages, included_kinds, excluded_kinds) = Self::symbol_filters(languages, kinds);
+    let search_keys = self.search_keys(search_paths);
+    // keys are bound as hex, as json can't hold arbitrary bytes
+    let hex_search_keys = search_keys
+      .iter()
+      .map(|(_, search_key)| {
+        search_key
+          .as_path()
+          .as_bytes()
+          .iter()
+          .map(|byte| format!("{byte:02x}"))
+          .collect::<String>()
+      })
+      .collect::<Vec<_>>();
+
+    sqlx::query_as::<_, CachedSymbol>(query)
+      .bind(fts_query)
+      .bind(term.as_str().to_string())
+      .bind(term.prefix_pattern())
+      .bind(term.segments_pattern())
+      .bind(languages)
+      .bind(included_kinds)
+      .bind(excluded_kinds)
+      .bind(serde_json::json!(hex_search_keys).to_string())
+      .bind(i64::try_from(limit).unwrap_or(i64::MAX))
+      .fetch(&self.pool)
+      .filter_map(move |row| Self::walked_symbol(&search_keys, row).ready())
   }
 
-  async fn delete_file(&self, file_path: &Path) -> Result<()> {
-    let file_path_bytes = file_path.as_bytes();
+  /// The json arrays of `languages` and of the included and excluded `kinds`
+  /// to bind to a query, where null matches everything.
+  ///
+  /// Languages are stored by their variant name and kinds by their
+  /// discriminant.
+  fn symbol_filters(languages: &HashSet<Language>, kinds: Option<&KindFilter>) -> (Option<String>, Option<String>, Option<String>) {
+    let kinds_json = |kinds: &HashSet<Kind>| serde_json::json!(kinds.iter().map(|kind| *kind as u8).collect::<Vec<_>>()).to_string();
 
-    sqlx::query("DELETE FROM file WHERE path = $1")
-      .bind(file_path_bytes)
-      .execute(&self.pool)
+    let languages = (!languages.is_empty())
+      .then(|| serde_json::json!(languages.iter().map(|language| format!("{language:?}")).collect::<Vec<_>>()).to_string());
+    let included_kinds = kinds
+      .map(KindFilter::included)
+      .filter(|included| !included.is_empty())
+      .map(kinds_json);
+    let excluded_kinds = kinds.map(KindFilter::excluded).map(kinds_json);
+
+    (languages, included_kinds, excluded_kinds)
+  }
+
+  /// Each of `search_paths` paired with its key.
+  fn search_keys(&self, search_paths: &[PathBuf]) -> Vec<(PathBuf, PathBuf)> {
+    search_paths
+      .iter()
+      .map(|search_path| (search_path.clone(), self.key(search_path)))
+      .collect()
+  }
+
+  /// The path of the file of a cached symbol as if the first of `search_keys`
+  /// containing it had been walked, or `None` if it is under none of them.
+  fn walked_symbol(search_keys: &[(PathBuf, PathBuf)], row: Result<CachedSymbol, sqlx::Error>) -> Option<Result<(PathBuf, Symbol)>> {
+    let cached_symbol = match row {
+      Ok(cached_symbol) => cached_symbol,
+      Err(err) => return Err(err).context("failed to get cached symbol").some(),
+    };
+    let key = PathBuf::from(cached_symbol.file_path);
+    let (search_path, relative_path) = search_keys
+      .iter()
+      .filter(|(_, search_key)| Self::is_under(&key, search_key))
+      .find_map(|(search_path, search_key)| key.strip_prefix(search_key).ok().map(|relative_path| (search_path, relative_path)))?;
+    let file_path = if relative_path.as_os_str().is_empty() {
+      search_path.to_path_buf()
+    } else {
+      search_path.join(relative_path)
+    };
+
+    (file_path, cached_symbol.symbol).ok().some()
+  }
+
+  /// The cached symbols of each of `file_paths` that has any, read with a
+  /// single query.
+  pub async fn get_symbols_of_files(&self, file_paths: &[PathBuf]) -> Result<HashMap<PathBuf, Vec<Symbol>>> {
+    let file_paths_by_key: HashMap<PathBuf, &PathBuf> = file_paths.iter().map(|file_path| (self.key(file_path), file_path)).collect();
+
+    let mut query = QueryBuilder::new("SELECT * FROM symbol WHERE file_path IN (");
+    let mut separated = query.separated(", ");
+    for key in file_paths_by_key.keys() {
+      separated.push_bind(key.as_path().as_bytes());
+    }
+    separated.push_unseparated(")");
+
+    let cached_symbols: Vec<CachedSymbol> = query
+      .build_query_as()
+      .fetch_all(&self.pool)
+      .await
+      .context("failed to get symbols")?;
+
+    let mut symbols = HashMap::<PathBuf, Vec<Symbol>>::new();
+    for cached_symbol in cached_symbols {
+      if let Some(file_path) = file_paths_by_key.get(&PathBuf::from(cached_symbol.file_path)) {
+        symbols.entry(file_path.to_path_buf()).or_default().push(cached_symbol.symbol);
+      }
+    }
+
+    symbols.ok()
+  }
+
+  /// The generation of the latest changes committed to the cache by any
+  /// process.
+  pub async fn generation(&self) -> Result<i64> {
+    sqlx::query_scalar("SELECT value FROM metadata WHERE key = 'generation'")
+      .fetch_one(&self.pool)
       .await
-      .context("failed to file")?;
+      .context("failed to get generation")
+  }
+
+  /// Deletes the cached files under `search_path` that are not in
+  /// `file_paths`, which are every file walked under it.
+  ///
+  /// Files written after `generation`, which is taken before walking, are
+  /// kept, as they may have been created since by another process.
+  pub async fn delete_stale_file_paths(&self, search_path: &Path, file_paths: &HashSet<PathBuf>, generation: i64) -> Result<()> {
+    let search_key = self.key(search_path);
+    let keys: HashSet<PathBuf> = file_paths.iter().map(|file_path| self.key(file_path)).collect();
+    let cached_keys = self.get_keys();
+    futures::pin_mut!(cached_keys);
+
+    while let Some(key) = cached_keys.next().await {
+      if Self::is_under(&key, &search_key) && !keys.contains(&key) {
+        self
+          .send(Message::Delete {
+            key,
+            generation: generation.some(),
+          })
+          .await?;
+      }
+    }
 
     ().ok()
   }
 
+  pub async fn delete_file(&self, file_path: &Path) -> Result<()> {
+    self
+      .send(Message::Delete {
+        key: self.key(file_path),
+        generation: None,
+      })
+      .await
+  }
+
   #[allow(deprecated)]
-  fn get_file_paths(&self) -> impl Stream<Item = PathBuf> {
+  fn get_keys(&self) -> impl Stream<Item = PathBuf> {
     sqlx::query_as::<Sqlite, RawPath>("SELECT path FROM file")
       .fetch_many(&self.pool)
       .filter_map(async |row| row.ok()?.right()?.convert::<PathBuf>().some())
   }
 
-  async fn from_options(options: SqliteConnectOptions) -> Result<Self> {
-    let pool = SqlitePool::connect_with(options)
+  /// Summarizes the files and symbols in the cache.
+  pub async fn stats(&self) -> Result<CacheStats> {
+    let path = self.path().await?;
+    let size = Self::size(&path).await;
+
+    let mut languages = HashMap::<Language, LanguageStats>::new();
+    let keys = self.get_keys();
+    futures::pin_mut!(keys);
+    while let Some(key) = keys.next().await {
+      if let Some(language) = Language::from_file_path(&key) {
+        languages.entry(language).or_insert_with(|| LanguageStats::new(language)).files += 1;
+      }
+    }
+
+    let kind_counts: Vec<(Language, Kind, i64)> =
+      sqlx::query_as("SELECT language, kind, COUNT(*) FROM symbol GROUP BY language, kind ORDER BY COUNT(*) DESC")
+        .fetch_all(&self.pool)
+        .await
+        .context("failed to count symbols")?;
+    for (language, kind, count) in kind_counts {
+      let language_stats = languages.entry(language).or_insert_with(|| LanguageStats::new(language));
+      language_stats.symbols += count;
+      language_stats.kinds.push((kind, count));
+    }
+
+    let mut languages: Vec<LanguageStats> = languages.into_values().collect();
+    languages.sort_by_key(|language_stats| std::cmp::Reverse(language_stats.symbols));
+
+    CacheStats {
+      path,
+      root: self.root.to_path_buf(),
+      size,
+      files: languages.iter().map(|language_stats| language_stats.files).sum(),
+      symbols: languages.iter().map(|language_stats| language_stats.symbols).sum(),
+      languages,
+    }
+    .ok()
+  }
+
+  /// The path of the database file of the cache.
+  async fn path(&self) -> Result<PathBuf> {
+    sqlx::query_scalar::<_, String>("SELECT file FROM pragma_database_list WHERE name = 'main'")
+      .fetch_one(&self.pool)
       .await
-      .context("failed to create sqlite connection pool")?;
-    let cache = Self { pool };
+      .map(PathBuf::from)
+      .context("failed to get cache path")
+  }
 
-    cache.initialize().await?;
+  /// The size in bytes of the database at `path` and its write-ahead log.
+  async fn size(path: &Path) -> u64 {
+    Self::database_metadata(path).await.iter().map(|(_, metadata)| metadata.len()).sum()
+  }
 
-    cache.ok()
+  /// The metadata of the database file at `path` and of its write-ahead log
+  /// files, if they exist, by the suffix of their path.
+  async fn database_metadata(path: &Path) -> Vec<(&'static str, std::fs::Metadata)> {
+    let mut metadata = Vec::new();
+    for suffix in ["", "-wal", "-shm"] {
+      let mut file_path = path.as_os_str().to_os_string();
+      file_path.push(suffix);
+      if let Ok(file_metadata) = tokio::fs::metadata(file_path).await {
+        metadata.push((suffix, file_metadata));
+      }
+    }
+
+    metadata
   }
 
-  async fn initialize(&self) -> Result<()> {
+  /// The caches of every project under [`Cache::projects_dir_path`], from
+  /// most to least recently used.
+  pub async fn project_caches() -> Result<Vec<ProjectCache>> {
+    let projects_dir_path = Self::projects_dir_path()?;
+    let mut entries = match tokio::fs::read_dir(&projects_dir_path).await {
+      Ok(entries) => entries,
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Vec::new().ok(),
+      Err(err) => return Err(err).with_context(|| format!("failed to read {}", projects_dir_path.display())),
+    };
+
+    let mut project_caches = Vec::new();
+    while let Some(entry) = entries.next_entry().await.context("failed to read project cache")? {
+      let path = entry.path();
+      let database_path = path.join(Self::CACHE_FILE_NAME);
+
+      // readers, including `read_stored_root`, touch the shared memory file
+      // and may create an empty log, so only writes count as uses
+      let metadata = Self::database_metadata(&database_path).await;
+      let Some(last_used) = metadata
+        .iter()
+        .filter(|(suffix, metadata)| *suffix != "-shm" && metadata.len() > 0)
+        .filter_map(|(_, metadata)| metadata.modified().ok())
+        .max()
+      else {
+        continue;
+      };
+
+      let root = Self::read_stored_root(&database_path).await;
+
+      project_caches.push(ProjectCache {
+        path,
+        root,
+        size: metadata.iter().map(|(_, metadata)| metadata.len()).sum(),
+        last_used: last_used.into(),
+      });
+    }
+
+    project_caches.sort_by(|a, b| b.last_used.cmp(&a.last_used));
+
+    project_caches.ok()
+  }
+
+  /// The root stored in the database at `database_path`, which is opened
+  /// read-only and without being migrated, so that neither the caches of
+  /// other projects nor those of other versions of cymbal are changed.
+  async fn read_stored_root(database_path: &Path) -> Result<Option<PathBuf>> {
+    let options = SqliteConnectOptions::new()
+      .filename(database_path)
+      .read_only(true)
+      .busy_timeout(Self::BUSY_TIMEOUT);
+    let mut connection = SqliteConnection::connect_with(&options)
+      .await
+      .with_context(|| format!("failed to open {}", database_path.display()))?;
+
+    let root = Self::stored_root(&mut connection).await;
+    connection.close().await.ignore();
+
+    root
+  }
+
+  /// Deletes every cached file that no longer exists, regardless of which
+  /// files were walked by the runs that cached them, returning how many were
+  /// deleted.
+  ///
+  /// Pending writes are committed first, and files are deleted by the
+  /// writer, so that the file infos stay in sync.
+  pub async fn gc(&self) -> Result<usize> {
+    self.flush().await?;
+    let keys: Vec<PathBuf> = self.get_keys().collect().await;
+
+    let mut deleted = 0;
+    for key in keys {
+      // keys outside of the root are absolute, which `join` keeps as is
+      if !self.root.join(&key).is_file() {
+        self.send(Message::Delete { key, generation: None }).await?;
+        deleted += 1;
+      }
+    }
+    self.flush().await?;
+
+    deleted.ok()
+  }
+
+  /// Deletes every cached file and symbol, after any pending writes.
+  pub async fn clear(&self) -> Result<()> {
+    self.send(Message::Clear).await?;
+
+    self.flush().await
+  }
+
+  /// Rebuilds the database to reclaim unused space, returning its size before
+  /// and after.
+  pub async fn vacuum(&self) -> Result<(u64, u64)> {
+    let path = self.path().await?;
+    let size = Self::size(&path).await;
+
+    sqlx::query("VACUUM").execute(&self.pool).await.context("failed to vacuum cache")?;
+
+    // vacuuming may renumber the rows of symbols, which the search index
+    // refers to
+    sqlx::query(
+      "
+        DELETE FROM symbol_search;
+        INSERT INTO symbol_search (rowid, name, segments, context)
+          SELECT rowid, content, segments, concat_ws(' ', leading, trailing) FROM symbol;
+        PRAGMA wal_checkpoint(TRUNCATE);
+      ",
+    )
+    .execute(&self.pool)
+    .await
+    .context("failed to rebuild search index")?;
+
+    (size, Self::size(&path).await).ok()
+  }
+
+  /// Up to `count` randomly chosen fully parsed files, or all of them if
+  /// `count` is 0, along with their info.
+  pub async fn sample_files(&self, count: usize) -> Result<Vec<(PathBuf, FileInfo)>> {
+    // a negative limit has no upper bound
+    let limit = if count == 0 { -1 } else { i64::try_from(count).unwrap_or(i64::MAX) };
+    let sampled_files: Vec<CachedFile> = sqlx::query_as(
+      "
+        SELECT path, modified, is_fully_parsed, fingerprint, content_hash FROM file
+          WHERE is_fully_parsed
+          ORDER BY RANDOM()
+          LIMIT $1
+      ",
+    )
+    .bind(limit)
+    .fetch_all(&self.pool)
+    .await
+    .context("failed to sample files")?;
+
+    sampled_files
+      .into_iter()
+      .map(|sampled_file| (self.root.join(PathBuf::from(sampled_file.path)), sampled_file.info))
+      .collect::<Vec<_>>()
+      .ok()
+  }
+
+  async fn connect(cache_dir_path: &Path, options: SqliteConnectOptions) -> Result<SqlitePool> {
+    let pool = SqlitePool::connect_with(options)
+      .await
+      .context("failed to create sqlite connection pool")?;
+
+    // processes opening the cache at the same time would otherwise race to
+    // apply the same migrations, so they are applied while holding a lock
+    let lock_file = std::fs::File::create(cache_dir_path.join(Self::LOCK_FILE_NAME)).context("failed to create lock file")?;
+    let lock_file = tokio::task::spawn_blocking(move || lock_file.lock().map(|()| lock_file))
+      .await?
+      .context("failed to lock cache")?;
+
     sqlx::migrate!("src/cache/migrations")
-      .run(&self.pool)
+      .run(&pool)
       .await
       .context("failed to migrate")?;
 
-    ().ok()
+    lock_file.unlock().context("failed to unlock cache")?;
+
+    pool.ok()
+  }
+}
+
+/// The info of every cached file by key, loaded with a single query the first
+/// time it is needed so that warm runs don't query the file table once per
+/// file, and then kept in sync with the file table.
+#[derive(Clone, Default)]
+struct Files(Arc<OnceCell<Mutex<FileInfos>>>);
+
+impl Files {
+  async fn get_or_load(&self, pool: &SqlitePool) -> Result<&Mutex<FileInfos>> {
+    self
+      .0
+      .get_or_try_init(async || {
+        let cached_files: Vec<CachedFile> = sqlx::query_as("SELECT path, modified, is_fully_parsed, fingerprint, content_hash FROM file")
+          .fetch_all(pool)
+          .await
+          .context("failed to get file infos")?;
+
+        let mut files = FileInfos::default();
+        for cached_file in cached_files {
+          files.insert(cached_file.path.into(), cached_file.info);
+        }
+
+        Mutex::new(files).ok()
+      })
+      .await
+  }
+
+  /// Applies `update` to the file infos, if they were loaded.
+  fn update(&self, update: impl FnOnce(&mut FileInfos)) {
+    if let Some(files) = self.0.get() {
+      update(&mut files.lock().expect("poisoned files lock"));
+    }
+  }
+}
+
+/// The info of every cached file by key, along with the keys of the fully
+/// parsed files by content hash, such as to find identical files.
+#[derive(Default)]
+struct FileInfos {
+  infos: HashMap<PathBuf, FileInfo>,
+  keys_by_content_hash: HashMap<i64, HashSet<PathBuf>>,
+}
+
+impl FileInfos {
+  fn get(&self, key: &Path) -> Option<&FileInfo> {
+    self.infos.get(key)
+  }
+
+  fn insert(&mut self, key: PathBuf, file_info: FileInfo) {
+    self.remove(&key);
+
+    if file_info.is_fully_parsed
+      && let Some(content_hash) = file_info.content_hash
+    {
+      self.keys_by_content_hash.entry(content_hash).or_default().insert(key.clone());
+    }
+    self.infos.insert(key, file_info).ignore();
+  }
+
+  fn remove(&mut self, key: &Path) {
+    let Some(file_info) = self.infos.remove(key) else { return };
+    let Some(content_hash) = file_info.content_hash else { return };
+
+    if let Some(keys) = self.keys_by_content_hash.get_mut(&content_hash) {
+      keys.remove(key).ignore();
+      if keys.is_empty() {
+        self.keys_by_content_hash.remove(&content_hash).ignore();
+      }
+    }
+  }
+
+  /// Removes every file with `content_hash`, such as once an identical file
+  /// turns out to have been deleted by another process.
+  fn remove_content_hash(&mut self, content_hash: i64) {
+    for key in self.keys_by_content_hash.remove(&content_hash).unwrap_or_default() {
+      self.infos.remove(&key).ignore();
+    }
+  }
+
+  fn set_modified(&mut self, key: &Path, modified: DateTime<Utc>) {
+    if let Some(file_info) = self.infos.get_mut(key) {
+      file_info.modified = modified;
+    }
+  }
+
+  fn clear(&mut self) {
+    self.infos.clear();
+    self.keys_by_content_hash.clear();
+  }
+
+  /// The key of a fully parsed file other than `key` with the same
+  /// `fingerprint` and `content_hash`, if any.
+  fn identical_key(&self, key: &Path, fingerprint: &str, content_hash: i64) -> Option<&Path> {
+    self
+      .keys_by_content_hash
+      .get(&content_hash)?
+      .iter()
+      .find(|identical_key| {
+        identical_key.as_path() != key
+          && self
+            .infos
+            .get(*identical_key)
+            .is_some_and(|file_info| file_info.fingerprint == fingerprint)
+      })
+      .map(PathBuf::as_path)
+  }
+}
+
+#[derive(sqlx::FromRow)]
+struct CachedFile {
+  path: RawPath,
+  #[sqlx(flatten)]
+  info: FileInfo,
+}
+
+#[derive(sqlx::FromRow)]
+struct CachedSymbol {
+  file_path: RawPath,
+  #[sqlx(flatten)]
+  symbol: Symbol,
+}
+
+/// Whether the cached symbols of a file are up to date, as looked up by
+/// [`Cache::lookup_file`].
+pub enum Lookup {
+  Hit,
+  /// The file is cached with the symbols of an identical file, which are
+  /// copied along with the writer's next batch.
+  Copied,
+  /// The file must be parsed, with its content if it was read to find out.
+  Miss(Option<Content>),
+}
+
+impl Lookup {
+  pub fn is_hit(&self) -> bool {
+    matches!(self, Self::Hit | Self::Copied)
+  }
+}
+
+/// The content of a file and its [`Cache::content_hash`].
+pub struct Content {
+  pub bytes: Vec<u8>,
+  pub hash: i64,
+}
+
+impl Content {
+  pub async fn read(file_path: &Path) -> Result<Self> {
+    let bytes = file_path.read_bytes().await?;
+
+    Self {
+      hash: Cache::content_hash(&bytes),
+      bytes,
+    }
+    .ok()
+  }
+}
+
+/// A summary of the contents of a [`Cache`].
+pub struct CacheStats {
+  /// The path of the database file.
+  pub path: PathBuf,
+  pub root: PathBuf,
+  /// The size in bytes of the database on disk.
+  pub size: u64,
+  pub files: i64,
+  pub symbols: i64,
+  /// The languages of the cached files, from most to fewest symbols.
+  pub languages: Vec<LanguageStats>,
+}
+
+pub struct LanguageStats {
+  pub language: Language,
+  pub files: i64,
+  pub symbols: i64,
+  /// The number of symbols of each kind, from most to fewest.
+  pub kinds: Vec<(Kind, i64)>,
+}
+
+impl LanguageStats {
+  fn new(language: Language) -> Self {
+    Self {
+      language,
+      files: 0,
+      symbols: 0,
+      kinds: Vec::new(),
+    }
+  }
+}
+
+/// A cache in [`Cache::projects_dir_path`].
+pub struct ProjectCache {
+  /// The directory of the cache.
+  pub path: PathBuf,
+  /// The root of the project, if the cache has one, or why it couldn't be
+  /// read.
+  pub root: Result<Option<PathBuf>>,
+  /// The size in bytes of the database on disk.
+  pub size: u64,
+  /// When the database was last modified, which every run does.
+  pub last_used: DateTime<Utc>,
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn row(file_path: &str) -> Result<CachedSymbol, sqlx::Error> {
+    CachedSymbol {
+      file_path: RawPath::from(PathBuf::from(file_path)),
+      symbol: Symbol::test(Kind::Function, "", "main", 1, 4),
+    }
+    .ok()
+  }
+
+  fn walked_path(search_keys: &[(&str, &str)], file_path: &str) -> Option<PathBuf> {
+    let search_keys: Vec<(PathBuf, PathBuf)> = search_keys
+      .iter()
+      .map(|(search_path, search_key)| (PathBuf::from(search_path), PathBuf::from(search_key)))
+      .collect();
+
+    Cache::walked_symbol(&search_keys, row(file_path)).map(|symbol| symbol.unwrap().0)
+  }
+
+  #[test]
+  fn walked_symbols_are_under_their_search_path() {
+    // `.` at the root, and a sibling of the root, whose files are keyed by
+    // their absolute path
+    let search_keys = [(".", ""), ("../shared", "/home/shared")];
+    assert_eq!(walked_path(&search_keys, "src/main.rs"), PathBuf::from("./src/main.rs").some());
+    assert_eq!(
+      walked_path(&search_keys, "/home/shared/lib.rs"),
+      PathBuf::from("../shared/lib.rs").some()
+    );
+    assert_eq!(walked_path(&search_keys, "/home/other/lib.rs"), None);
+    assert_eq!(walked_path(&search_keys, "/home/shared2/lib.rs"), None);
+
+    // a file search path is its own file path
+    assert_eq!(walked_path(&[("main.rs", "main.rs")], "main.rs"), PathBuf::from("main.rs").some());
+    assert_eq!(
+      walked_path(&[("src", "project/src")], "project/src/main.rs"),
+      PathBuf::from("src/main.rs").some()
+    );
+    assert_eq!(walked_path(&[("src", "project/src")], "project/tests/main.rs"), None);
   }
 }
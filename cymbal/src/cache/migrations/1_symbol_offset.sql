@@ -0,0 +1,5 @@
+ALTER TABLE symbol ADD COLUMN offset INTEGER NOT NULL DEFAULT 0;
+
+-- symbols cached before offsets were stored must be reparsed
+DELETE FROM symbol;
+DELETE FROM file;
@@ -0,0 +1,12 @@
+ALTER TABLE symbol ADD COLUMN end_line INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE symbol ADD COLUMN end_column INTEGER NOT NULL DEFAULT 0;
+
+-- the range of the enclosing `@definition` capture, if the query has one
+ALTER TABLE symbol ADD COLUMN definition_line INTEGER;
+ALTER TABLE symbol ADD COLUMN definition_column INTEGER;
+ALTER TABLE symbol ADD COLUMN definition_end_line INTEGER;
+ALTER TABLE symbol ADD COLUMN definition_end_column INTEGER;
+
+-- symbols cached before ranges were stored must be reparsed
+DELETE FROM symbol;
+DELETE FROM file;
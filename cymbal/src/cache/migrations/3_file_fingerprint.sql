@@ -0,0 +1,3 @@
+-- files cached before fingerprints were stored have an empty fingerprint, and
+-- so are reparsed
+ALTER TABLE file ADD COLUMN fingerprint TEXT NOT NULL DEFAULT '';
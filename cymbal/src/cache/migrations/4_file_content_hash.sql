@@ -0,0 +1,5 @@
+-- a hash of the content of the file when it was parsed, which is NULL for files
+-- cached before content hashes were stored
+ALTER TABLE file ADD COLUMN content_hash INTEGER;
+
+CREATE INDEX files_for_content_hash ON file (content_hash);
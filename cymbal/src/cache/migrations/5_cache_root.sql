@@ -0,0 +1,10 @@
+-- properties of the cache as a whole, such as the root that file paths are
+-- relative to
+CREATE TABLE metadata (
+  key TEXT NOT NULL PRIMARY KEY,
+  value BLOB NOT NULL
+);
+
+-- files cached by their path as walked must be reparsed
+DELETE FROM symbol;
+DELETE FROM file;
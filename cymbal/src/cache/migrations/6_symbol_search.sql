@@ -0,0 +1,28 @@
+-- the lowercase camelCase and snake_case segments of the content, each
+-- preceded by a space, such as " file symbols" for `fileSymbols`
+ALTER TABLE symbol ADD COLUMN segments TEXT NOT NULL DEFAULT '';
+
+-- a trigram index of symbols for substring matching, which stores no content
+-- of its own and is kept in sync with the symbol table by triggers
+CREATE VIRTUAL TABLE symbol_search USING fts5 (
+  name,
+  segments,
+  -- the leading and trailing text
+  context,
+  content = '',
+  contentless_delete = 1,
+  tokenize = 'trigram'
+);
+
+CREATE TRIGGER symbol_search_insert AFTER INSERT ON symbol BEGIN
+  INSERT INTO symbol_search (rowid, name, segments, context)
+    VALUES (new.rowid, new.content, new.segments, concat_ws(' ', new.leading, new.trailing));
+END;
+
+CREATE TRIGGER symbol_search_delete AFTER DELETE ON symbol BEGIN
+  DELETE FROM symbol_search WHERE rowid = old.rowid;
+END;
+
+-- symbols cached before segments were stored must be reparsed
+DELETE FROM symbol;
+DELETE FROM file;
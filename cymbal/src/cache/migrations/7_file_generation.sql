@@ -0,0 +1,6 @@
+-- the generation of the transaction that last wrote each file, where every
+-- transaction starts a new generation, so that a walk only deletes the stale
+-- files last written before it started, while the last write of a file wins
+ALTER TABLE file ADD COLUMN generation INTEGER NOT NULL DEFAULT 0;
+
+INSERT INTO metadata (key, value) VALUES ('generation', 0);
@@ -0,0 +1,400 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use sqlx::{QueryBuilder, SqliteConnection, SqlitePool};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+  cache::Files,
+  ext::{Ignore, IntoExt, PathExt},
+  search,
+  symbol::{FileInfo, Symbol},
+};
+
+/// A change to the cache, where files are identified by their key.
+pub enum Message {
+  /// Caches a fully parsed file and its symbols, replacing any previous ones.
+  Write {
+    key: PathBuf,
+    modified: DateTime<Utc>,
+    fingerprint: String,
+    content_hash: i64,
+    symbols: Vec<Symbol>,
+  },
+  /// Caches a file with the symbols of an identical cached file.
+  Copy {
+    key: PathBuf,
+    identical_key: PathBuf,
+    modified: DateTime<Utc>,
+    fingerprint: String,
+    content_hash: i64,
+  },
+  SetModified {
+    key: PathBuf,
+    modified: DateTime<Utc>,
+  },
+  /// Deletes a file, but only if it was last written no later than
+  /// `generation`, if set.
+  Delete {
+    key: PathBuf,
+    generation: Option<i64>,
+  },
+  /// Deletes every file.
+  Clear,
+  /// Responds once every previous message is committed, with the first error
+  /// since the last flush, if any.
+  Flush(oneshot::Sender<Result<()>>),
+}
+
+/// Writes changes to the cache from a single task, committing as many pending
+/// changes as possible per transaction, so that concurrent workers neither
+/// contend for the database nor commit once per statement.
+///
+/// Every transaction starts a new generation, which the files it writes are
+/// marked with, so that stale cleanup can leave files written since a walk
+/// started, such as by another process sharing the cache.
+pub struct Writer {
+  pool: SqlitePool,
+  files: Files,
+  receiver: mpsc::Receiver<Message>,
+  /// The first error since the last flush.
+  error: Option<anyhow::Error>,
+}
+
+impl Writer {
+  /// The maximum number of messages committed per transaction, which is also
+  /// the number of messages that can be pending before senders wait.
+  const BATCH_SIZE: usize = 256;
+
+  /// Spawns a writer for the cache in `pool`, which runs until every sender
+  /// is dropped.
+  pub fn spawn(pool: SqlitePool, files: Files) -> mpsc::Sender<Message> {
+    let (sender, receiver) = mpsc::channel(Self::BATCH_SIZE);
+    let writer = Self {
+      pool,
+      files,
+      receiver,
+      error: None,
+    };
+
+    tokio::spawn(writer.run()).ignore();
+
+    sender
+  }
+
+  async fn run(mut self) {
+    let mut messages = Vec::with_capacity(Self::BATCH_SIZE);
+
+    while self.receiver.recv_many(&mut messages, Self::BATCH_SIZE).await > 0 {
+      let mut flushes = Vec::new();
+      let mut changes = Vec::with_capacity(messages.len());
+      for message in messages.drain(..) {
+        match message {
+          Message::Flush(sender) => flushes.push(sender),
+          change => changes.push(change),
+        }
+      }
+
+      if !changes.is_empty() {
+        match self.commit(&changes).await {
+          Ok(results) => {
+            let applied: Vec<bool> = results
+              .into_iter()
+              .map(|result| {
+                result.unwrap_or_else(|err| {
+                  self.error.get_or_insert(err);
+
+                  false
+                })
+              })
+              .collect();
+            self.update_files(changes, &applied);
+          }
+          Err(err) => {
+            self.error.get_or_insert(err);
+          }
+        }
+      }
+
+      for flush in flushes {
+        flush.send(self.error.take().map_or_else(|| ().ok(), Err)).ignore();
+      }
+    }
+  }
+
+  /// Commits `changes` in a single transaction with a new generation,
+  /// returning whether each was applied. Writes always are, so the last
+  /// commit wins, while other changes are skipped for a missing file, copies
+  /// also for a missing identical file, and stale deletions also for a file
+  /// written after their generation.
+  ///
+  /// A change that fails is rolled back on its own, returning its error,
+  /// while the rest of the batch is still committed.
+  async fn commit(&self, changes: &[Message]) -> Result<Vec<Result<bool>>> {
+    // the write lock is taken up front, as upgrading a read transaction to a
+    // write transaction fails immediately if another process is writing,
+    // rather than waiting for the busy timeout
+    let mut transaction = self
+      .pool
+      .begin_with("BEGIN IMMEDIATE")
+      .await
+      .context("failed to begin transaction")?;
+
+    let generation: i64 = sqlx::query_scalar("UPDATE metadata SET value = value + 1 WHERE key = 'generation' RETURNING value")
+      .fetch_one(&mut *transaction)
+      .await
+      .context("failed to start generation")?;
+
+    let mut results = Vec::with_capacity(changes.len());
+    for change in changes {
+      sqlx::query("SAVEPOINT change")
+        .execute(&mut *transaction)
+        .await
+        .context("failed to start change")?;
+
+      let result = Self::apply(&mut transaction, change, generation).await;
+      let end_change = if result.is_ok() {
+        "RELEASE change"
+      } else {
+        "ROLLBACK TO change; RELEASE change"
+      };
+      sqlx::query(end_change)
+        .execute(&mut *transaction)
+        .await
+        .context("failed to end change")?;
+
+      results.push(result);
+    }
+
+    transaction.commit().await.context("failed to commit transaction")?;
+
+    results.ok()
+  }
+
+  async fn apply(connection: &mut SqliteConnection, change: &Message, generation: i64) -> Result<bool> {
+    let is_applied = match change {
+      Message::Write {
+        key,
+        modified,
+        fingerprint,
+        content_hash,
+        symbols,
+      } => {
+        Self::insert_file(connection, key, modified, fingerprint, *content_hash, generation).await?;
+        Self::delete_symbols(connection, key).await?;
+        for symbols_chunk in symbols.chunks(100) {
+          Self::insert_symbols(connection, key, symbols_chunk).await?;
+        }
+
+        true
+      }
+      Message::Copy {
+        key,
+        identical_key,
+        modified,
+        fingerprint,
+        content_hash,
+      } => {
+        // the identical file may have been deleted since the copy was sent
+        let is_identical_cached: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM file WHERE path = $1 AND is_fully_parsed)")
+          .bind(identical_key.as_path().as_bytes())
+          .fetch_one(&mut *connection)
+          .await
+          .context("failed to find identical file")?;
+        if !is_identical_cached {
+          return false.ok();
+        }
+
+        Self::insert_file(connection, key, modified, fingerprint, *content_hash, generation).await?;
+        Self::delete_symbols(connection, key).await?;
+        sqlx::query(
+          "
+            INSERT INTO symbol (
+              file_path, kind, language, line, column, offset, end_line, end_column,
+              definition_line, definition_column, definition_end_line, definition_end_column,
+              content, leading, trailing, segments
+            )
+            SELECT
+              $1, kind, language, line, column, offset, end_line, end_column,
+              definition_line, definition_column, definition_end_line, definition_end_column,
+              content, leading, trailing, segments
+            FROM symbol WHERE file_path = $2
+          ",
+        )
+        .bind(key.as_path().as_bytes())
+        .bind(identical_key.as_path().as_bytes())
+        .execute(&mut *connection)
+        .await
+        .context("failed to copy symbols")?;
+
+        true
+      }
+      Message::SetModified { key, modified } => {
+        sqlx::query("UPDATE file SET modified = $2, generation = $3 WHERE path = $1")
+          .bind(key.as_path().as_bytes())
+          .bind(modified)
+          .bind(generation)
+          .execute(&mut *connection)
+          .await
+          .context("failed to set modified for file info")?
+          .rows_affected()
+          > 0
+      }
+      Message::Delete { key, generation } => {
+        sqlx::query("DELETE FROM file WHERE path = $1 AND ($2 IS NULL OR generation <= $2)")
+          .bind(key.as_path().as_bytes())
+          .bind(generation)
+          .execute(&mut *connection)
+          .await
+          .context("failed to delete file")?
+          .rows_affected()
+          > 0
+      }
+      Message::Clear => {
+        sqlx::query("DELETE FROM symbol; DELETE FROM file;")
+          .execute(&mut *connection)
+          .await
+          .context("failed to clear cache")?;
+
+        true
+      }
+      Message::Flush(_) => false,
+    };
+
+    is_applied.ok()
+  }
+
+  /// Inserts the info of a file, which is fully parsed as its symbols are
+  /// written in the same transaction.
+  async fn insert_file(
+    connection: &mut SqliteConnection,
+    key: &Path,
+    modified: &DateTime<Utc>,
+    fingerprint: &str,
+    content_hash: i64,
+    generation: i64,
+  ) -> Result<()> {
+    sqlx::query(
+      "
+        INSERT INTO file (path, modified, fingerprint, content_hash, is_fully_parsed, generation)
+          VALUES ($1, $2, $3, $4, TRUE, $5)
+        ON CONFLICT DO UPDATE SET
+          modified = excluded.modified,
+          fingerprint = excluded.fingerprint,
+          content_hash = excluded.content_hash,
+          is_fully_parsed = TRUE,
+          generation = excluded.generation
+      ",
+    )
+    .bind(key.as_bytes())
+    .bind(modified)
+    .bind(fingerprint)
+    .bind(content_hash)
+    .bind(generation)
+    .execute(connection)
+    .await
+    .map(Ignore::ignore)
+    .context("failed to insert file info")
+  }
+
+  async fn delete_symbols(connection: &mut SqliteConnection, key: &Path) -> Result<()> {
+    sqlx::query("DELETE FROM symbol WHERE file_path = $1")
+      .bind(key.as_bytes())
+      .execute(connection)
+      .await
+      .map(Ignore::ignore)
+      .context("failed to delete stale symbols")
+  }
+
+  async fn insert_symbols(connection: &mut SqliteConnection, key: &Path, symbols: &[Symbol]) -> Result<()> {
+    let file_path_bytes = key.as_bytes();
+    let mut query = QueryBuilder::new(
+      "
+        INSERT INTO symbol (
+          file_path, kind, language, line, column, offset, end_line, end_column,
+          definition_line, definition_column, definition_end_line, definition_end_column,
+          content, leading, trailing, segments
+        )
+      ",
+    );
+    query.push_values(symbols, |mut query, symbol| {
+      query.push_bind(file_path_bytes);
+      query.push_bind(symbol.kind);
+      query.push_bind(symbol.language);
+      query.push_bind(symbol.line);
+      query.push_bind(symbol.column);
+      query.push_bind(symbol.offset);
+      query.push_bind(symbol.end_line);
+      query.push_bind(symbol.end_column);
+      query.push_bind(symbol.definition_line);
+      query.push_bind(symbol.definition_column);
+      query.push_bind(symbol.definition_end_line);
+      query.push_bind(symbol.definition_end_column);
+      query.push_bind(&symbol.content);
+      query.push_bind(&symbol.leading);
+      query.push_bind(&symbol.trailing);
+      query.push_bind(search::segments(&symbol.content));
+    });
+
+    query
+      .build()
+      .execute(connection)
+      .await
+      .map(Ignore::ignore)
+      .context("failed to insert symbols")
+  }
+
+  /// Applies committed changes to the in-memory file infos, forgetting the
+  /// files of skipped changes, whose info was written by another process, as
+  /// well as the files identical to those of skipped copies.
+  fn update_files(&self, changes: Vec<Message>, applied: &[bool]) {
+    self.files.update(|files| {
+      for (change, &is_applied) in changes.into_iter().zip(applied) {
+        if !is_applied {
+          match change {
+            Message::Copy { key, content_hash, .. } => {
+              files.remove(&key);
+              files.remove_content_hash(content_hash);
+            }
+            Message::Write { key, .. } | Message::SetModified { key, .. } | Message::Delete { key, .. } => {
+              files.remove(&key);
+            }
+            Message::Clear | Message::Flush(_) => {}
+          }
+
+          continue;
+        }
+
+        match change {
+          Message::Write {
+            key,
+            modified,
+            fingerprint,
+            content_hash,
+            ..
+          }
+          | Message::Copy {
+            key,
+            modified,
+            fingerprint,
+            content_hash,
+            ..
+          } => {
+            let file_info = FileInfo {
+              modified,
+              is_fully_parsed: true,
+              fingerprint,
+              content_hash: content_hash.some(),
+            };
+            files.insert(key, file_info);
+          }
+          Message::SetModified { key, modified } => files.set_modified(&key, modified),
+          Message::Delete { key, .. } => files.remove(&key),
+          Message::Clear => files.clear(),
+          Message::Flush(_) => {}
+        }
+      }
+    });
+  }
+}
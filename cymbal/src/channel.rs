@@ -2,7 +2,7 @@ use std::path::PathBuf;
 
 use chrono::{DateTime, Utc};
 
-use crate::config::Language;
+use crate::{config::Language, ext::IntoExt};
 
 pub struct FileTask {
   pub file_path: PathBuf,
@@ -18,6 +18,18 @@ impl FileTask {
       language,
     }
   }
+
+  /// Creates a task for `file_path` if it is a file in a supported language.
+  pub fn from_file_path(file_path: PathBuf) -> Option<Self> {
+    let metadata = file_path.metadata().ok()?;
+    if !metadata.is_file() {
+      return None;
+    }
+    let file_modified = metadata.modified().ok()?;
+    let language = Language::from_file_path(&file_path)?;
+
+    Self::new(file_path, file_modified.into(), language).some()
+  }
 }
 
 pub type Sender = async_channel::Sender<FileTask>;
@@ -1,20 +1,25 @@
 mod raw;
 
-use std::{collections::HashMap, ffi::OsStr, path::Path};
+use std::{
+  collections::{HashMap, HashSet},
+  ffi::OsStr,
+  path::Path,
+};
 
 use anyhow::{Context, Result};
 use clap::ValueEnum;
 use enum_assoc::Assoc;
 use indexmap::IndexMap;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use sqlx::Type as SqlxType;
 use tree_sitter::Query as TreeSitterQuery;
 
 use crate::{
   color::{BLUE, BRIGHT_YELLOW, CYAN, GREEN, MAGENTA, YELLOW},
   config::raw::{DEFAULT_CONFIG, RawConfig},
-  ext::{PathExt, TomlExt},
-  symbol::Kind,
+  ext::{IntoExt, LazyExt, PathExt, TomlExt},
+  symbol::{Kind, KindFilter},
   template::Template,
   utils::{Colored, Lazy},
 };
@@ -23,6 +28,11 @@ include!(concat!(env!("OUT_DIR"), "/", "grammars.rs"));
 
 pub struct Config {
   languages: HashMap<Language, Lazy<LanguageQuery>>,
+  /// Identifies the symbols each language's queries produce, so that cached
+  /// symbols from different queries or grammars are not reused.
+  fingerprints: HashMap<Language, String>,
+  /// The kinds of symbols to emit, if not all of them.
+  kinds: Option<KindFilter>,
 }
 
 impl Config {
@@ -36,16 +46,71 @@ impl Config {
     self.languages.contains_key(&language)
   }
 
-  pub fn for_language(self, language: Language) -> Self {
+  pub fn for_languages(self, languages: &HashSet<Language>) -> Self {
     Self {
       languages: self
         .languages
         .into_iter()
-        .filter(|(config_lang, _)| config_lang == &language)
+        .filter(|(config_lang, _)| languages.contains(config_lang))
         .collect(),
+      fingerprints: self.fingerprints,
+      kinds: self.kinds,
+    }
+  }
+
+  /// Restricts symbols to those of `kinds`, skipping the queries of excluded
+  /// kinds where this doesn't affect the precedence of included kinds.
+  pub fn for_kinds(self, kinds: KindFilter) -> Self {
+    let languages = self
+      .languages
+      .into_iter()
+      .map(|(language, query)| {
+        let kinds = kinds.clone();
+        let query = Lazy::new(Box::new(move || query.take().for_kinds(&kinds)));
+
+        (language, query)
+      })
+      .collect();
+
+    Self {
+      languages,
+      fingerprints: self.fingerprints,
+      kinds: kinds.some(),
     }
   }
 
+  pub fn fingerprint(&self, language: Language) -> Option<&str> {
+    self.fingerprints.get(&language).map(String::as_str)
+  }
+
+  /// A hash of the fingerprint of every language, which changes whenever the
+  /// symbols of any language could.
+  pub fn languages_fingerprint(&self) -> String {
+    let mut fingerprints: Vec<(String, &str)> = self
+      .fingerprints
+      .iter()
+      .map(|(language, fingerprint)| (format!("{language:?}"), fingerprint.as_str()))
+      .collect();
+    fingerprints.sort_unstable();
+
+    let mut hasher = Sha256::new();
+    for (language, fingerprint) in fingerprints {
+      hasher.update(format!("{language}\0{fingerprint}\0"));
+    }
+
+    format!("{:x}", hasher.finalize())
+  }
+
+  pub fn contains_kind(&self, kind: Kind) -> bool {
+    self.kinds.as_ref().is_none_or(|kinds| kinds.contains(kind))
+  }
+
+  /// Whether some kinds of symbols are not parsed, in which case parsed
+  /// symbols must not be cached.
+  pub fn is_partial(&self) -> bool {
+    self.kinds.is_some()
+  }
+
   pub fn queries_for_language(&self, language: Language) -> Option<&Lazy<LanguageQuery>> {
     self.languages.get(&language)
   }
@@ -81,6 +146,8 @@ pub struct QuerySource {
 pub struct LanguageQuery {
   ts: TreeSitterQuery,
   symbol_index: u32,
+  /// The optional capture spanning the entire definition of a symbol.
+  definition_index: Option<u32>,
   /// Indexed by tree-sitter pattern index.
   patterns: Vec<PatternMeta>,
 }
@@ -147,6 +214,7 @@ impl LanguageQuery {
     let symbol_index = ts
       .capture_index_for_name("symbol")
       .context("combined query has no @symbol capture")?;
+    let definition_index = ts.capture_index_for_name("definition");
 
     let patterns = metas
       .into_iter()
@@ -165,10 +233,68 @@ impl LanguageQuery {
     Ok(Self {
       ts,
       symbol_index,
+      definition_index,
       patterns,
     })
   }
 
+  /// A hash of everything that determines the symbols `queries` produce: the
+  /// queries themselves, the grammar of `language`, and the version of cymbal.
+  pub fn fingerprint(language: Language, queries: &Queries) -> String {
+    let ts_language = language.as_tree_sitter_language();
+    let mut hasher = Sha256::new();
+
+    hasher.update(env!("CARGO_PKG_VERSION"));
+    hasher.update(format!(
+      "{:?}:{}:{}:{}:{}",
+      ts_language.metadata().map(|m| (m.major_version, m.minor_version, m.patch_version)),
+      ts_language.abi_version(),
+      ts_language.node_kind_count(),
+      ts_language.field_count(),
+      ts_language.parse_state_count(),
+    ));
+
+    for (kind, query_sources) in queries {
+      for query_source in query_sources {
+        // fields are separated by a null byte so that they cannot run together
+        hasher.update(format!(
+          "\0{}\0{}\0{}\0{}",
+          kind.name(),
+          query_source.source,
+          query_source.leading.as_deref().unwrap_or_default(),
+          query_source.trailing.as_deref().unwrap_or_default(),
+        ));
+      }
+    }
+
+    format!("{:x}", hasher.finalize())
+  }
+
+  /// Disables the patterns of kinds not in `kinds` that appear after every
+  /// pattern of an included kind.
+  ///
+  /// Patterns earlier in the configuration take precedence over later ones at
+  /// the same position, so an excluded pattern appearing before an included
+  /// one must still run, and its symbols be discarded afterwards, to prevent
+  /// the included pattern from matching in its place. For example, methods
+  /// would otherwise be emitted as functions when only functions are included.
+  pub fn for_kinds(mut self, kinds: &KindFilter) -> Self {
+    let last_included = self
+      .patterns
+      .iter()
+      .filter(|pattern| kinds.contains(pattern.kind))
+      .map(|pattern| pattern.source_ordinal)
+      .max();
+
+    for (index, pattern) in self.patterns.iter().enumerate() {
+      if !kinds.contains(pattern.kind) && last_included.is_none_or(|last_included| pattern.source_ordinal > last_included) {
+        self.ts.disable_pattern(index);
+      }
+    }
+
+    self
+  }
+
   pub fn tree_sitter_query(&self) -> &TreeSitterQuery {
     &self.ts
   }
@@ -177,6 +303,10 @@ impl LanguageQuery {
     self.symbol_index
   }
 
+  pub fn definition_index(&self) -> Option<u32> {
+    self.definition_index
+  }
+
   pub fn pattern(&self, index: usize) -> &PatternMeta {
     &self.patterns[index]
   }
@@ -184,7 +314,7 @@ impl LanguageQuery {
 
 #[allow(clippy::unsafe_derive_deserialize)]
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Assoc, Clone, Copy, Debug, Deserialize, PartialEq, Eq, Hash, SqlxType, ValueEnum)]
+#[derive(Assoc, Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Hash, SqlxType, ValueEnum)]
 #[serde(rename_all = "lowercase")]
 #[value(rename_all = "lowercase")]
 #[func(pub fn from_extension(s: &str) -> Option<Self>)]
@@ -261,4 +391,22 @@ mod tests {
   fn config_default_no_panic() {
     Config::default();
   }
+
+  #[test]
+  fn fingerprint_changes_with_queries() {
+    let queries = |leading: Option<&str>| {
+      Queries::from([(
+        Kind::Function,
+        vec![QuerySource {
+          source: "(function_item name: (identifier) @symbol)".to_string(),
+          leading: leading.map(str::to_string),
+          trailing: None,
+        }],
+      )])
+    };
+
+    let fingerprint = LanguageQuery::fingerprint(Language::Rust, &queries(None));
+    assert_eq!(fingerprint, LanguageQuery::fingerprint(Language::Rust, &queries(None)));
+    assert_ne!(fingerprint, LanguageQuery::fingerprint(Language::Rust, &queries(Some("fn "))));
+  }
 }
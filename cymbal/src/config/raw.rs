@@ -99,6 +99,11 @@ impl From<RawConfig> for Config {
     let provided_config = raw_config.provided_config();
     let merged = RawConfig::merge_inherited_and_provided_configs(inherited_config, provided_config);
 
+    let fingerprints = merged
+      .iter()
+      .map(|(language, queries)| (*language, LanguageQuery::fingerprint(*language, queries)))
+      .collect();
+
     let languages = merged
       .into_iter()
       .map(|(language, queries)| {
@@ -110,7 +115,11 @@ impl From<RawConfig> for Config {
       })
       .collect();
 
-    Config { languages }
+    Config {
+      languages,
+      fingerprints,
+      kinds: None,
+    }
   }
 }
 
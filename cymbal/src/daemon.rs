@@ -0,0 +1,160 @@
+use std::{
+  path::{Path, PathBuf},
+  time::Duration,
+};
+
+use anyhow::{Context, Result};
+use tokio::{
+  io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+  net::{UnixListener, UnixStream},
+  sync::Mutex,
+};
+
+use crate::{
+  ext::{IntoExt, Leak},
+  format::Format,
+  index::Index,
+  watcher::Watcher,
+};
+
+/// A long-running process that keeps the symbols under its search paths
+/// indexed, reparsing files as they change, and answers queries over a Unix
+/// socket.
+///
+/// Each connection sends a single line, and receives every symbol whose
+/// content contains that line (ignoring case) before the connection is closed.
+/// An empty line matches every symbol.
+pub struct Daemon {
+  index: &'static Index,
+  format: Format,
+  search_paths: Vec<PathBuf>,
+  concurrency: usize,
+  /// Held while an answer is formatted, as the state of [`Format::Json`] is
+  /// shared by every answer.
+  formatting: Mutex<()>,
+}
+
+impl Daemon {
+  /// How long a connection has to send its query before it is closed.
+  const QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+  pub fn new(index: &'static Index, format: Format, search_paths: Vec<PathBuf>, concurrency: usize) -> Self {
+    Self {
+      index,
+      format,
+      search_paths,
+      concurrency,
+      formatting: Mutex::new(()),
+    }
+  }
+
+  pub async fn run(self, socket_path: &Path) -> Result<()> {
+    anyhow::ensure!(
+      !matches!(self.format, Format::Ctags { .. } | Format::Etags { .. }),
+      "tags files cannot be written by the daemon",
+    );
+
+    let listener = Self::bind(socket_path).await?;
+    let watcher = Watcher::new(&self.search_paths, self.index.walk_options())?;
+
+    for search_path in &self.search_paths {
+      for err in self.index.index_path(search_path, self.concurrency).await? {
+        eprintln!("{err:#}");
+      }
+    }
+
+    let mut reindexer = tokio::spawn(Self::reindex_changes(self.index, watcher));
+    // connections are answered concurrently, so that a slow client doesn't
+    // hold up the others
+    let daemon = self.leak();
+
+    loop {
+      tokio::select! {
+        result = listener.accept() => {
+          let (stream, _) = result.context("failed to accept connection")?;
+          tokio::spawn(async move {
+            if let Err(err) = daemon.answer(stream).await {
+              eprintln!("{err:#}");
+            }
+          });
+        }
+
+        // the reindexer only stops on a fatal error, such as the watcher
+        // stopping, after which the index would silently go stale
+        result = &mut reindexer => {
+          tokio::fs::remove_file(socket_path).await.context("failed to remove socket")?;
+          result.context("reindexer panicked")?.context("failed to reindex changes")?;
+          anyhow::bail!("reindexer stopped");
+        }
+
+        result = tokio::signal::ctrl_c() => {
+          result.context("failed to wait for ctrl-c")?;
+          break;
+        }
+      }
+    }
+
+    reindexer.abort();
+    tokio::fs::remove_file(socket_path).await.context("failed to remove socket")
+  }
+
+  /// Binds to `socket_path`, replacing any socket left behind by a daemon that
+  /// is no longer running.
+  async fn bind(socket_path: &Path) -> Result<UnixListener> {
+    if socket_path.exists() {
+      anyhow::ensure!(
+        UnixStream::connect(socket_path).await.is_err(),
+        "a daemon is already listening on {}",
+        socket_path.display(),
+      );
+
+      tokio::fs::remove_file(socket_path).await.context("failed to remove stale socket")?;
+    }
+
+    UnixListener::bind(socket_path).context("failed to bind socket")
+  }
+
+  async fn reindex_changes(index: &'static Index, mut watcher: Watcher) -> Result<()> {
+    loop {
+      for file_path in watcher.changed_paths().await? {
+        if let Err(err) = index.update(&file_path).await {
+          eprintln!("{err:#}");
+        }
+      }
+    }
+  }
+
+  /// Answers a single query, closing the connection if it isn't sent within
+  /// [`Self::QUERY_TIMEOUT`].
+  async fn answer(&self, stream: UnixStream) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut query = String::new();
+    tokio::time::timeout(Self::QUERY_TIMEOUT, BufReader::new(reader).read_line(&mut query))
+      .await
+      .context("timed out reading query")?
+      .context("failed to read query")?;
+    let query = query.trim_end_matches(['\r', '\n']).to_lowercase();
+
+    let output = self.matching_symbols(&query).await?;
+    writer.write_all(&output).await.context("failed to write answer")?;
+
+    ().ok()
+  }
+
+  /// The formatted output of every symbol whose content contains `query`.
+  async fn matching_symbols(&self, query: &str) -> Result<Vec<u8>> {
+    let files = self.index.files().await;
+    let _formatting = self.formatting.lock().await;
+
+    let mut output = Vec::new();
+    self.format.write_start(&mut output)?;
+    for (file_path, symbols) in files.iter() {
+      for symbol in symbols.iter().filter(|symbol| symbol.content.to_lowercase().contains(query)) {
+        self.format.write_symbol(&mut output, file_path, symbol)?;
+      }
+    }
+    self.format.write_end(&mut output).await?;
+
+    output.ok()
+  }
+}
@@ -0,0 +1,155 @@
+use std::{
+  collections::BTreeMap,
+  path::{Path, PathBuf},
+  sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+
+use crate::{ext::PathExt, symbol::Symbol};
+
+/// An Emacs `TAGS` file.
+///
+/// Entries are grouped into one section per file, and are only written once
+/// all files have been processed.
+pub struct Etags {
+  path: PathBuf,
+  entries: Mutex<BTreeMap<PathBuf, Vec<Entry>>>,
+}
+
+struct Entry {
+  name: String,
+  line: i64,
+  column: i64,
+  offset: i64,
+}
+
+impl Etags {
+  pub fn new(path: PathBuf) -> Self {
+    Self {
+      path,
+      entries: Mutex::new(BTreeMap::new()),
+    }
+  }
+
+  pub fn push(&self, file_path: &Path, symbol: &Symbol) {
+    let entry = Entry {
+      name: symbol.content.clone(),
+      line: symbol.line,
+      column: symbol.column,
+      offset: symbol.offset,
+    };
+
+    let mut entries = self.entries.lock().expect("poisoned etags lock");
+    entries.entry(file_path.to_path_buf()).or_default().push(entry);
+  }
+
+  /// Writes a section for every file with symbols to the `TAGS` file.
+  ///
+  /// Each entry's tag text is the line it is on, up to and including the
+  /// symbol, which is what Emacs searches for to find the tag. Files are read
+  /// again to recover this text.
+  pub async fn write(&self) -> Result<()> {
+    let entries = std::mem::take(&mut *self.entries.lock().expect("poisoned etags lock"));
+    let mut content = Vec::new();
+
+    for (file_path, mut file_entries) in entries {
+      let Ok(file_bytes) = tokio::fs::read(&file_path).await else { continue };
+      file_entries.sort_unstable_by_key(|entry| entry.offset);
+
+      let mut section = Vec::new();
+      for entry in &file_entries {
+        section.extend(Self::entry(&file_bytes, entry));
+      }
+
+      let tags_file_path = self.tags_file_path(&file_path);
+      content.extend(format!("\x0c\n{},{}\n", tags_file_path.display(), section.len()).into_bytes());
+      content.extend(section);
+    }
+
+    tokio::fs::write(&self.path, content).await.context("failed to write etags file")
+  }
+
+  /// The path of `file_path` as written to the `TAGS` file, which Emacs
+  /// resolves against the directory of the `TAGS` file. Files under that
+  /// directory are relative to it, and others are absolute.
+  fn tags_file_path(&self, file_path: &Path) -> PathBuf {
+    let file_path = file_path.normalized();
+    let tags_dir_path = self.path.normalized().parent().map(Path::to_path_buf).unwrap_or_default();
+
+    file_path
+      .strip_prefix(&tags_dir_path)
+      .map_or_else(|_| file_path.clone(), Path::to_path_buf)
+  }
+
+  #[allow(clippy::cast_sign_loss, clippy::cast_possible_truncation)]
+  fn entry(file_bytes: &[u8], entry: &Entry) -> Vec<u8> {
+    let line_start = (entry.offset - (entry.column - 1)) as usize;
+    let symbol_end = (entry.offset as usize + entry.name.len()).min(file_bytes.len());
+    let tag_text = &file_bytes[line_start.min(symbol_end)..symbol_end];
+    let tag_text = tag_text.split(|byte| *byte == b'\n').next().unwrap_or_default();
+
+    let mut entry_bytes = tag_text.to_vec();
+    entry_bytes.extend(format!("\x7f{}\x01{},{}\n", entry.name, entry.line, line_start).into_bytes());
+
+    entry_bytes
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::symbol::Kind;
+
+  fn function(content: &str, line: i64, column: i64, offset: i64) -> Symbol {
+    Symbol {
+      offset,
+      ..Symbol::test(Kind::Function, "", content, line, column)
+    }
+  }
+
+  #[tokio::test]
+  async fn sections_are_measured_in_bytes() {
+    let dir = tempfile::tempdir().unwrap();
+    let file_path = dir.path().join("main.rs");
+    // columns and offsets are in bytes, and "é" and "ü" are 2 bytes each
+    std::fs::write(&file_path, "fn café() {}\n// ü\nlet ü = 1; fn size() {}\n").unwrap();
+
+    let etags = Etags::new(dir.path().join("TAGS"));
+    etags.push(&file_path, &function("size", 3, 16, 35));
+    etags.push(&file_path, &function("café", 1, 4, 3));
+    etags.write().await.unwrap();
+
+    let section = "fn café\x7fcafé\x011,0\nlet ü = 1; fn size\x7fsize\x013,20\n";
+    assert_eq!(section.len(), 49);
+    assert_eq!(
+      std::fs::read(dir.path().join("TAGS")).unwrap(),
+      format!("\x0c\nmain.rs,49\n{section}").into_bytes(),
+    );
+  }
+
+  #[tokio::test]
+  async fn file_names_are_relative_to_tags_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let tags_dir_path = dir.path().join("project");
+    let inside_path = tags_dir_path.join("src/main.rs");
+    let outside_path = dir.path().join("shared/lib.rs");
+    for file_path in [&inside_path, &outside_path] {
+      std::fs::create_dir_all(file_path.parent().unwrap()).unwrap();
+      std::fs::write(file_path, "fn f() {}\n").unwrap();
+    }
+
+    let etags = Etags::new(tags_dir_path.join("TAGS"));
+    // walked paths are resolved lexically
+    etags.push(&tags_dir_path.join("src/../src/main.rs"), &function("f", 1, 4, 3));
+    etags.push(&outside_path, &function("f", 1, 4, 3));
+    etags.write().await.unwrap();
+
+    let content = String::from_utf8(std::fs::read(tags_dir_path.join("TAGS")).unwrap()).unwrap();
+    let file_names: Vec<&str> = content
+      .split('\x0c')
+      .filter_map(|section| section.lines().nth(1)?.split(',').next())
+      .collect();
+    assert_eq!(file_names, ["src/main.rs", outside_path.to_str().unwrap()]);
+  }
+}
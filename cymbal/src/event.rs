@@ -0,0 +1,141 @@
+use std::{
+  collections::{HashMap, VecDeque},
+  io::Write,
+};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::{
+  config::Language,
+  ext::IntoExt,
+  symbol::{Kind, Symbol},
+};
+
+/// A change to the symbols of a single file.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum Event<'a> {
+  Added {
+    path: &'a str,
+    #[serde(flatten)]
+    symbol: &'a Symbol,
+  },
+  Removed {
+    path: &'a str,
+    #[serde(flatten)]
+    symbol: &'a Symbol,
+  },
+  /// A symbol whose kind, content, and leading/trailing text are unchanged,
+  /// but whose position is not.
+  Moved {
+    path: &'a str,
+    #[serde(flatten)]
+    symbol: &'a Symbol,
+    from: Position,
+  },
+}
+
+/// The previous position of a moved symbol.
+#[derive(Debug, Serialize)]
+pub struct Position {
+  pub line: i64,
+  pub column: i64,
+  pub offset: i64,
+}
+
+/// The parts of a symbol that identify it across changes to its file.
+type Identity<'a> = (Kind, Language, &'a str, Option<&'a str>, Option<&'a str>);
+
+impl<'a> Event<'a> {
+  /// The events that turn the `previous` symbols of the file at `path` into its
+  /// `current` symbols.
+  ///
+  /// Symbols with the same identity are paired in the order they appear, so
+  /// inserting a symbol before an identical one results in the latter being
+  /// added rather than every identical symbol moving. Removals are listed
+  /// first, followed by additions and moves in the order of `current`.
+  pub fn diff(path: &'a str, previous: &'a [Symbol], current: &'a [Symbol]) -> Vec<Self> {
+    let mut unmatched = HashMap::<Identity, VecDeque<&Symbol>>::new();
+    for symbol in previous {
+      unmatched.entry(Self::identity(symbol)).or_default().push_back(symbol);
+    }
+
+    let mut changes = Vec::new();
+    for symbol in current {
+      match unmatched.get_mut(&Self::identity(symbol)).and_then(VecDeque::pop_front) {
+        Some(previous) if previous.offset == symbol.offset => {}
+        Some(previous) => changes.push(Self::Moved {
+          path,
+          symbol,
+          from: Position {
+            line: previous.line,
+            column: previous.column,
+            offset: previous.offset,
+          },
+        }),
+        None => changes.push(Self::Added { path, symbol }),
+      }
+    }
+
+    let mut removed: Vec<&Symbol> = unmatched.into_values().flatten().collect();
+    removed.sort_unstable_by_key(|symbol| symbol.offset);
+
+    removed
+      .into_iter()
+      .map(|symbol| Self::Removed { path, symbol })
+      .chain(changes)
+      .collect()
+  }
+
+  fn identity(symbol: &Symbol) -> Identity<'_> {
+    (
+      symbol.kind,
+      symbol.language,
+      &symbol.content,
+      symbol.leading.as_deref(),
+      symbol.trailing.as_deref(),
+    )
+  }
+
+  /// Writes this event as a single line of JSON.
+  pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+    let json = serde_json::to_string(self).context("failed to serialize event")?;
+    writeln!(writer, "{json}").context("failed to write event")?;
+
+    ().ok()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn symbol(content: &str, line: i64) -> Symbol {
+    Symbol {
+      offset: line * 10,
+      definition_line: line.some(),
+      definition_column: 1.some(),
+      definition_end_line: line.some(),
+      definition_end_column: 10.some(),
+      ..Symbol::test(Kind::Function, "", content, line, 4)
+    }
+  }
+
+  #[test]
+  fn diff_pairs_symbols_by_identity() {
+    let previous = [symbol("a", 1), symbol("b", 2), symbol("c", 3)];
+    let current = [symbol("a", 1), symbol("d", 2), symbol("c", 4)];
+
+    let events = Event::diff("lib.rs", &previous, &current)
+      .into_iter()
+      .map(|event| match event {
+        Event::Added { symbol, .. } => format!("+{}", symbol.content),
+        Event::Removed { symbol, .. } => format!("-{}", symbol.content),
+        Event::Moved { symbol, from, .. } => format!("{}:{}->{}", symbol.content, from.line, symbol.line),
+      })
+      .collect::<Vec<_>>();
+
+    assert_eq!(events, ["-b", "+d", "c:3->4"]);
+  }
+}
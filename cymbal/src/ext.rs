@@ -4,7 +4,7 @@ use std::{
   borrow::Borrow,
   collections::HashMap,
   hash::Hash,
-  path::{Path, PathBuf},
+  path::{Component, Path, PathBuf},
 };
 
 use anyhow::{Context, Result};
@@ -128,6 +128,27 @@ pub impl<T> T {
     self.is().as_os_str().as_encoded_bytes()
   }
 
+  /// The absolute form of this path, with `.` and `..` components resolved
+  /// lexically rather than by following symlinks.
+  fn normalized(&self) -> PathBuf
+  where
+    Self: AsRef<Path>,
+  {
+    let path = self.as_ref();
+    let absolute_path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+
+    let mut normalized_path = PathBuf::new();
+    for component in absolute_path.components() {
+      match component {
+        Component::CurDir => {}
+        Component::ParentDir => normalized_path.pop().ignore(),
+        component => normalized_path.push(component),
+      }
+    }
+
+    normalized_path
+  }
+
   async fn read_bytes(&self) -> Result<Vec<u8>>
   where
     Self: AsRef<Path>,
@@ -0,0 +1,156 @@
+use std::{
+  borrow::Cow,
+  cmp::Reverse,
+  io::Write,
+  path::{Path, PathBuf},
+  sync::{
+    Mutex,
+    atomic::{AtomicUsize, Ordering},
+  },
+};
+
+use anyhow::{Context, Result};
+use fuzzy_matcher::{FuzzyMatcher, skim::SkimMatcherV2};
+use regex::{Regex, RegexBuilder};
+
+use crate::{ext::IntoExt, format::Format, symbol::Symbol};
+
+/// Restricts which symbols are written, by matching their text and by limiting
+/// how many are written.
+pub struct Filter {
+  matcher: Option<Matcher>,
+  /// Whether the leading and trailing text of symbols is matched along with
+  /// their content.
+  context: bool,
+  limit: Option<usize>,
+  /// The number of symbols that have been allowed to be written immediately.
+  written: AtomicUsize,
+  /// Symbols matching a fuzzy query along with their scores. These are only
+  /// written once all symbols have been scored.
+  ranked: Mutex<Vec<(i64, PathBuf, Symbol)>>,
+}
+
+pub enum Matcher {
+  Regex(Regex),
+  Fuzzy { matcher: Box<SkimMatcherV2>, query: String },
+}
+
+impl Matcher {
+  pub fn regex(pattern: &str, ignore_case: bool) -> Result<Self> {
+    let regex = RegexBuilder::new(pattern)
+      .case_insensitive(ignore_case)
+      .build()
+      .context("failed to compile regex")?;
+
+    Self::Regex(regex).ok()
+  }
+
+  /// A fuzzy matcher for `query`, which unless `ignore_case` is set, is
+  /// case-sensitive only if `query` contains uppercase characters.
+  pub fn fuzzy(query: &str, ignore_case: bool) -> Self {
+    let matcher = SkimMatcherV2::default();
+    let matcher = Box::new(if ignore_case { matcher.ignore_case() } else { matcher.smart_case() });
+
+    Self::Fuzzy {
+      matcher,
+      query: query.to_string(),
+    }
+  }
+}
+
+impl Filter {
+  pub fn new(matcher: Option<Matcher>, context: bool, limit: Option<usize>) -> Self {
+    Self {
+      matcher,
+      context,
+      limit,
+      written: AtomicUsize::new(0),
+      ranked: Mutex::new(Vec::new()),
+    }
+  }
+
+  fn text<'a>(&self, symbol: &'a Symbol) -> Cow<'a, str> {
+    if self.context {
+      format!("{}{}{}", symbol.leading_str(), symbol.content, symbol.trailing_str()).into()
+    } else {
+      symbol.content.as_str().into()
+    }
+  }
+
+  /// Whether `symbol` should be written immediately.
+  ///
+  /// Symbols matching a fuzzy query are held back, and are instead written in
+  /// order of their score by [`Filter::write_ranked`].
+  pub fn should_write(&self, file_path: &Path, symbol: &Symbol) -> bool {
+    match &self.matcher {
+      Some(Matcher::Fuzzy { matcher, query }) => {
+        if let Some(score) = matcher.fuzzy_match(&self.text(symbol), query) {
+          let mut ranked = self.ranked.lock().expect("poisoned ranked lock");
+          ranked.push((score, file_path.to_path_buf(), symbol.clone()));
+        }
+
+        false
+      }
+      Some(Matcher::Regex(regex)) if !regex.is_match(&self.text(symbol)) => false,
+      Some(Matcher::Regex(_)) | None => self.limit.is_none_or(|limit| self.written.fetch_add(1, Ordering::Relaxed) < limit),
+    }
+  }
+
+  /// Writes the symbols held back by [`Filter::should_write`], from best to
+  /// worst match.
+  pub fn write_ranked<W: Write>(&self, format: &Format, writer: &mut W) -> Result<()> {
+    let mut ranked = self.ranked.lock().expect("poisoned ranked lock");
+    // ties are broken by position so that the output is deterministic
+    ranked.sort_unstable_by(|(a_score, a_path, a), (b_score, b_path, b)| {
+      (Reverse(a_score), a_path, a.offset).cmp(&(Reverse(b_score), b_path, b.offset))
+    });
+
+    for (_, file_path, symbol) in ranked.iter().take(self.limit.unwrap_or(usize::MAX)) {
+      format.write_symbol(writer, file_path, symbol)?;
+    }
+
+    ().ok()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::symbol::Kind;
+
+  fn symbol(content: &str, offset: i64) -> Symbol {
+    Symbol {
+      offset,
+      ..Symbol::test(Kind::Function, "Cache::", content, 1, 1)
+    }
+  }
+
+  #[test]
+  fn fuzzy_matches_are_ranked_and_limited() {
+    let filter = Filter::new(Matcher::fuzzy("fromdir", false).some(), false, 2.some());
+    let format = Format::JsonLines;
+
+    for (offset, content) in ["from_dirpath", "fn_or_dir", "from_dir", "unrelated"].into_iter().enumerate() {
+      assert!(!filter.should_write(Path::new("cache.rs"), &symbol(content, offset as i64)));
+    }
+
+    let mut output = Vec::new();
+    filter.write_ranked(&format, &mut output).unwrap();
+    let contents = String::from_utf8(output)
+      .unwrap()
+      .lines()
+      .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["content"].to_string())
+      .collect::<Vec<_>>();
+
+    // both score equally, and so are ordered by position
+    assert_eq!(contents, [r#""from_dirpath""#, r#""from_dir""#]);
+  }
+
+  #[test]
+  fn regex_matches_context() {
+    let regex = || Matcher::regex("^cache::from", true).unwrap().some();
+
+    assert!(!Filter::new(regex(), false, None).should_write(Path::new("cache.rs"), &symbol("from_dirpath", 0)));
+    assert!(Filter::new(regex(), true, None).should_write(Path::new("cache.rs"), &symbol("from_dirpath", 0)));
+  }
+}
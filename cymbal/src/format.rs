@@ -0,0 +1,189 @@
+use std::{io::Write, path::Path, sync::Mutex};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::{etags::Etags, ext::IntoExt, symbol::Symbol, tags::Tags};
+
+/// How symbols are written by a [`Worker`](crate::worker::Worker).
+#[derive(Clone, Copy)]
+pub enum Format {
+  /// Symbol properties separated by `delimiter`, and symbols separated by
+  /// `separator`.
+  ///
+  /// If `ranges` is set, the end of each symbol and the range of its
+  /// definition follow its trailing text, so that the positions of the other
+  /// properties are unchanged.
+  Text {
+    delimiter: char,
+    separator: char,
+    color: bool,
+    ranges: bool,
+  },
+  /// A single JSON array of symbols.
+  ///
+  /// `is_first` is shared by every worker writing to the same array, and is
+  /// held while a symbol is written so that commas are placed correctly.
+  Json { is_first: &'static Mutex<bool> },
+  /// One JSON object per symbol, each on its own line.
+  JsonLines,
+  /// A universal-ctags compatible `tags` file, which is written once all
+  /// symbols have been collected instead of to the worker's writer.
+  Ctags { tags: &'static Tags },
+  /// An Emacs `TAGS` file, which is written once all symbols have been
+  /// collected instead of to the worker's writer.
+  Etags { etags: &'static Etags },
+}
+
+/// The JSON representation of a symbol found at `path`.
+#[derive(Serialize)]
+struct JsonSymbol<'a> {
+  path: &'a str,
+  #[serde(flatten)]
+  symbol: &'a Symbol,
+}
+
+impl Format {
+  /// Writes anything that must precede all symbols.
+  pub fn write_start<W: Write>(&self, writer: &mut W) -> Result<()> {
+    match self {
+      Self::Json { is_first } => {
+        *is_first.lock().expect("poisoned json lock") = true;
+
+        write!(writer, "[").context("failed to write start")
+      }
+      Self::Text { .. } | Self::JsonLines | Self::Ctags { .. } | Self::Etags { .. } => ().ok(),
+    }
+  }
+
+  /// Writes anything that must follow all symbols.
+  pub async fn write_end<W: Write>(&self, writer: &mut W) -> Result<()> {
+    match self {
+      Self::Json { .. } => writeln!(writer, "]").context("failed to write end"),
+      Self::Ctags { tags } => tags.write(),
+      Self::Etags { etags } => etags.write().await,
+      Self::Text { .. } | Self::JsonLines => ().ok(),
+    }
+  }
+
+  /// Reuses previously written output for `file_path` instead of writing its
+  /// symbols again, returning whether this was possible, as it wasn't
+  /// modified since.
+  pub fn reuse_previous(&self, file_path: &Path, file_modified: &DateTime<Utc>) -> bool {
+    match self {
+      Self::Ctags { tags } => tags.reuse_previous(file_path, file_modified),
+      Self::Text { .. } | Self::Json { .. } | Self::JsonLines | Self::Etags { .. } => false,
+    }
+  }
+
+  pub fn write_symbol<W: Write>(&self, writer: &mut W, file_path: &Path, symbol: &Symbol) -> Result<()> {
+    match *self {
+      Self::Text {
+        delimiter,
+        separator,
+        color,
+        ranges,
+      } => {
+        // the ranges are formatted first so that each symbol is written at
+        // once, as workers writing to stdout must not interleave
+        let ranges = if ranges {
+          let optional = |value: Option<i64>| value.map(|value| value.to_string()).unwrap_or_default();
+
+          format!(
+            "{dlm}{end_line}{dlm}{end_col}{dlm}{def_line}{dlm}{def_col}{dlm}{def_end_line}{dlm}{def_end_col}",
+            end_line = symbol.end_line,
+            end_col = symbol.end_column,
+            def_line = optional(symbol.definition_line),
+            def_col = optional(symbol.definition_column),
+            def_end_line = optional(symbol.definition_end_line),
+            def_end_col = optional(symbol.definition_end_column),
+            dlm = delimiter,
+          )
+        } else {
+          String::new()
+        };
+
+        write!(
+          writer,
+          "{lang}{dlm}{kind}{dlm}{path}{dlm}{line}{dlm}{col}{dlm}{lead}{dlm}{text}{dlm}{trail}{ranges}{separator}",
+          lang = symbol.language.colored(color),
+          kind = symbol.kind.colored(color),
+          path = file_path.display(),
+          line = symbol.line,
+          col = symbol.column,
+          lead = symbol.leading_str(),
+          text = symbol.content,
+          trail = symbol.trailing_str(),
+          dlm = delimiter,
+        )
+        .context("failed to write symbol")
+      }
+
+      Self::Json { is_first } => {
+        let json = Self::to_json(file_path, symbol)?;
+        let mut is_first = is_first.lock().expect("poisoned json lock");
+        let comma = if *is_first { "" } else { "," };
+        *is_first = false;
+
+        write!(writer, "{comma}{json}").context("failed to write symbol")
+      }
+
+      Self::JsonLines => {
+        let json = Self::to_json(file_path, symbol)?;
+
+        writeln!(writer, "{json}").context("failed to write symbol")
+      }
+
+      Self::Ctags { tags } => {
+        tags.push(file_path, symbol);
+
+        ().ok()
+      }
+
+      Self::Etags { etags } => {
+        etags.push(file_path, symbol);
+
+        ().ok()
+      }
+    }
+  }
+
+  fn to_json(file_path: &Path, symbol: &Symbol) -> Result<String> {
+    let path = file_path.to_string_lossy();
+
+    serde_json::to_string(&JsonSymbol { path: &path, symbol }).context("failed to serialize symbol")
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::symbol::Kind;
+
+  #[test]
+  fn json_lines_are_unpadded() {
+    let symbol = Symbol {
+      definition_line: 1.some(),
+      definition_column: 1.some(),
+      definition_end_line: 3.some(),
+      definition_end_column: 2.some(),
+      ..Symbol::test(Kind::Function, "", "main", 1, 4)
+    };
+
+    let mut output = Vec::new();
+    Format::JsonLines
+      .write_symbol(&mut output, Path::new("src/main.rs"), &symbol)
+      .unwrap();
+
+    assert_eq!(
+      String::from_utf8(output).unwrap(),
+      concat!(
+        r#"{"path":"src/main.rs","kind":"function","language":"rust","line":1,"column":4,"offset":3,"end_line":1,"end_column":8,"#,
+        r#""definition_line":1,"definition_column":1,"definition_end_line":3,"definition_end_column":2,"#,
+        r#""content":"main","leading":null,"trailing":null}"#,
+        "\n"
+      ),
+    );
+  }
+}
@@ -0,0 +1,188 @@
+use std::{
+  collections::HashMap,
+  path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use futures::{Stream, StreamExt};
+use tokio::sync::{Mutex, RwLock, RwLockReadGuard};
+
+use crate::{
+  cache::{Cache, Content, Lookup},
+  channel::{self, FileTask},
+  config::{Config, Language},
+  ext::{Ignore, IntoExt, TryStreamExt},
+  parser::Parser,
+  symbol::Symbol,
+  walker::{WalkOptions, Walker},
+  worker::UniqueSymbolsExt,
+};
+
+/// The symbols of a set of files, held in memory for long-running modes.
+pub struct Index {
+  config: &'static Config,
+  cache: Option<Cache>,
+  walk_options: &'static WalkOptions,
+  /// Symbols keyed by file path, as walked or as provided to [`Index::update`].
+  files: RwLock<HashMap<PathBuf, Vec<Symbol>>>,
+}
+
+impl Index {
+  pub fn new(config: &'static Config, cache: Option<Cache>, walk_options: &'static WalkOptions) -> Self {
+    Self::with_files(config, cache, walk_options, HashMap::new())
+  }
+
+  /// Creates an index of the symbols of `files`, such as those just listed.
+  pub fn with_files(
+    config: &'static Config,
+    cache: Option<Cache>,
+    walk_options: &'static WalkOptions,
+    files: HashMap<PathBuf, Vec<Symbol>>,
+  ) -> Self {
+    Self {
+      config,
+      cache,
+      walk_options,
+      files: RwLock::new(files),
+    }
+  }
+
+  pub fn walk_options(&self) -> &'static WalkOptions {
+    self.walk_options
+  }
+
+  pub async fn files(&self) -> RwLockReadGuard<'_, HashMap<PathBuf, Vec<Symbol>>> {
+    self.files.read().await
+  }
+
+  /// Walks `path` and indexes the symbols of every file in it, parsing up to
+  /// `concurrency` files at a time.
+  ///
+  /// Files that fail to be indexed do not stop the walk, and their errors are
+  /// returned instead.
+  pub async fn index_path(&self, path: &Path, concurrency: usize) -> Result<Vec<anyhow::Error>> {
+    let (sender, receiver) = channel::unbounded();
+    // stale entries are not cleaned, as the walked paths may differ from those
+    // of other invocations sharing the cache
+    let walker = Walker::new(path.to_path_buf(), sender, self.cache.clone(), false, self.walk_options).spawn();
+    let errors = Mutex::new(Vec::new());
+
+    receiver
+      .for_each_concurrent(concurrency, async |file_task| match self.file_symbols(&file_task).await {
+        Ok(symbols) => self.files.write().await.insert(file_task.file_path, symbols).ignore(),
+        Err(err) => errors.lock().await.push(err),
+      })
+      .await;
+
+    walker.await??;
+
+    let mut errors = errors.into_inner();
+    if let Some(cache) = &self.cache
+      && let Err(err) = cache.flush().await
+    {
+      errors.push(err);
+    }
+
+    errors.ok()
+  }
+
+  /// Reindexes `file_path` from disk, removing it if it no longer exists,
+  /// returning its previous and current symbols.
+  pub async fn update(&self, file_path: &Path) -> Result<(Vec<Symbol>, Vec<Symbol>)> {
+    let symbols = match FileTask::from_file_path(file_path.to_path_buf()) {
+      Some(file_task) => self.file_symbols(&file_task).await?,
+      None => {
+        if let Some(cache) = &self.cache {
+          cache.delete_file(file_path).await?;
+        }
+
+        Vec::new()
+      }
+    };
+
+    self.replace(file_path, symbols).await.ok()
+  }
+
+  /// Reindexes `file_path` from its in-memory `text`, such as an unsaved editor
+  /// buffer, returning its previous and current symbols.
+  pub async fn update_text(&self, file_path: &Path, text: &str) -> Result<(Vec<Symbol>, Vec<Symbol>)> {
+    let symbols = match Language::from_file_path(file_path) {
+      Some(language) if self.config.contains_language(language) => {
+        let symbol_stream = Parser::new(file_path, language, self.config)
+          .symbol_stream_from_bytes(text.as_bytes().to_vec())
+          .await?;
+
+        self.included_symbols(symbol_stream).await
+      }
+      _ => Vec::new(),
+    };
+
+    self.replace(file_path, symbols).await.ok()
+  }
+
+  async fn replace(&self, file_path: &Path, symbols: Vec<Symbol>) -> (Vec<Symbol>, Vec<Symbol>) {
+    let mut files = self.files.write().await;
+    let previous = if symbols.is_empty() {
+      files.remove(file_path)
+    } else {
+      files.insert(file_path.to_path_buf(), symbols.clone())
+    };
+
+    (previous.unwrap_or_default(), symbols)
+  }
+
+  /// The symbols of a file on disk, read from the cache if it is up to date.
+  async fn file_symbols(&self, file_task: &FileTask) -> Result<Vec<Symbol>> {
+    let FileTask {
+      file_path,
+      file_modified,
+      language,
+    } = file_task;
+
+    if !self.config.contains_language(*language) {
+      return Vec::new().ok();
+    }
+
+    let fingerprint = self.config.fingerprint(*language).unwrap_or_default();
+    let content = match &self.cache {
+      Some(cache) => match cache.lookup_file(file_path, file_modified, fingerprint).await? {
+        Lookup::Hit => return self.included_symbols(cache.get_symbols(file_path).filter_ok()).await.ok(),
+        Lookup::Copied => {
+          cache.flush().await?;
+
+          return self.included_symbols(cache.get_symbols(file_path).filter_ok()).await.ok();
+        }
+        Lookup::Miss(content) => content,
+      },
+      None => None,
+    };
+    let Content { bytes, hash } = match content {
+      Some(content) => content,
+      None => Content::read(file_path).await?,
+    };
+    let symbol_stream = Parser::new(file_path, *language, self.config)
+      .symbol_stream_from_bytes(bytes)
+      .await?;
+    let symbols = self.included_symbols(symbol_stream).await;
+
+    if let Some(cache) = &self.cache
+      && !self.config.is_partial()
+    {
+      cache
+        .write_file(file_path, file_modified, fingerprint, hash, symbols.clone())
+        .await?;
+    }
+
+    symbols.ok()
+  }
+
+  /// The unique symbols of `symbol_stream` whose kinds are included by the
+  /// config.
+  async fn included_symbols(&self, symbol_stream: impl Stream<Item = Symbol>) -> Vec<Symbol> {
+    symbol_stream
+      .unique_symbols()
+      .filter(|symbol| self.config.contains_kind(symbol.kind).ready())
+      .collect()
+      .await
+  }
+}
@@ -6,8 +6,14 @@ mod template;
 pub mod cache;
 pub mod channel;
 pub mod config;
+pub mod etags;
+pub mod event;
 pub mod ext;
+pub mod filter;
+pub mod format;
 pub mod parser;
+pub mod search;
 pub mod symbol;
+pub mod tags;
 pub mod utils;
 pub mod worker;
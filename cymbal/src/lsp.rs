@@ -0,0 +1,355 @@
+use std::{
+  collections::HashMap,
+  path::{Path, PathBuf},
+  sync::OnceLock,
+};
+
+use anyhow::Context;
+use tokio::sync::RwLock;
+use tower_lsp::{
+  Client, LanguageServer, LspService, Server, jsonrpc,
+  lsp_types::{
+    DidChangeTextDocumentParams, DidCloseTextDocumentParams, DidOpenTextDocumentParams, DidSaveTextDocumentParams, DocumentSymbolParams,
+    DocumentSymbolResponse, InitializeParams, InitializeResult, InitializedParams, Location, MessageType, OneOf, Position,
+    PositionEncodingKind, Range, SaveOptions, ServerCapabilities, ServerInfo, SymbolInformation, SymbolKind, TextDocumentSyncCapability,
+    TextDocumentSyncKind, TextDocumentSyncOptions, TextDocumentSyncSaveOptions, Url, WorkspaceSymbolParams,
+  },
+};
+
+use crate::{
+  cache::Cache,
+  config::Config,
+  ext::{Ignore, IntoExt},
+  index::Index,
+  symbol::{Kind, Symbol},
+  walker::WalkOptions,
+};
+
+/// A language server answering `workspace/symbol` and
+/// `textDocument/documentSymbol` requests over stdio.
+pub struct Lsp {
+  client: Client,
+  concurrency: usize,
+  /// The directory indexed if the client does not provide a root.
+  search_path: PathBuf,
+  root: OnceLock<PathBuf>,
+  /// The symbols of every file under the root, keyed by absolute path. Open
+  /// documents hold the symbols of their unsaved content.
+  index: Index,
+  /// The content of every open document, which positions are converted with.
+  documents: RwLock<HashMap<PathBuf, String>>,
+  /// Whether the client accepts positions in UTF-8 code units, as symbols'
+  /// columns are, rather than the UTF-16 code units that LSP defaults to.
+  is_utf8: OnceLock<bool>,
+}
+
+impl Lsp {
+  pub async fn serve(
+    config: &'static Config,
+    cache: Option<Cache>,
+    walk_options: &'static WalkOptions,
+    concurrency: usize,
+    search_path: PathBuf,
+  ) {
+    let (service, socket) = LspService::new(|client| Self {
+      client,
+      concurrency,
+      search_path,
+      root: OnceLock::new(),
+      index: Index::new(config, cache, walk_options),
+      documents: RwLock::new(HashMap::new()),
+      is_utf8: OnceLock::new(),
+    });
+
+    Server::new(tokio::io::stdin(), tokio::io::stdout(), socket).serve(service).await;
+  }
+
+  /// Reparses a document, either from its in-memory `text` or from disk.
+  async fn reindex(&self, uri: &Url, text: Option<&str>) {
+    let Ok(file_path) = uri.to_file_path() else { return };
+
+    let result = match text {
+      Some(text) => {
+        self.documents.write().await.insert(file_path.clone(), text.to_string()).ignore();
+        self.index.update_text(&file_path, text).await
+      }
+      None => {
+        self.documents.write().await.remove(&file_path).ignore();
+        self.index.update(&file_path).await
+      }
+    };
+
+    if let Err(err) = result {
+      self.log_error(err).await;
+    }
+  }
+
+  async fn log_error(&self, err: anyhow::Error) {
+    self.client.log_message(MessageType::ERROR, format!("{err:#}")).await;
+  }
+
+  /// The information of `symbols` of `file_path`, whose positions are
+  /// converted to UTF-16 code units unless the client accepts UTF-8.
+  async fn symbol_informations(&self, file_path: &Path, symbols: &[Symbol]) -> Vec<SymbolInformation> {
+    let text = if self.is_utf8.get().copied().unwrap_or_default() {
+      None
+    } else {
+      self.text(file_path).await
+    };
+
+    symbols
+      .iter()
+      .filter_map(|symbol| Self::symbol_information(file_path, symbol, text.as_deref()))
+      .collect()
+  }
+
+  /// The content of `file_path`, which is unsaved if it is open.
+  async fn text(&self, file_path: &Path) -> Option<String> {
+    if let Some(text) = self.documents.read().await.get(file_path) {
+      return text.clone().some();
+    }
+
+    tokio::fs::read_to_string(file_path).await.ok()
+  }
+
+  /// The information of a `symbol` of `file_path`, whose positions are in
+  /// UTF-16 code units of `text` if it is provided, and otherwise in bytes.
+  #[allow(deprecated)]
+  fn symbol_information(file_path: &Path, symbol: &Symbol, text: Option<&str>) -> Option<SymbolInformation> {
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let position = |line: i64, column: i64| Position::new((line - 1) as u32, (column - 1) as u32);
+
+    // a symbol that is stale with respect to `text` keeps its byte columns
+    let range = text
+      .and_then(|text| Self::utf16_range(text, symbol))
+      .unwrap_or_else(|| Range::new(position(symbol.line, symbol.column), position(symbol.end_line, symbol.end_column)));
+
+    SymbolInformation {
+      name: symbol.content.clone(),
+      kind: Self::symbol_kind(symbol.kind),
+      tags: None,
+      deprecated: None,
+      location: Location {
+        uri: Url::from_file_path(file_path).ok()?,
+        range,
+      },
+      container_name: symbol.scope().map(str::to_string),
+    }
+    .some()
+  }
+
+  /// The range of `symbol` in UTF-16 code units of `text`, or `None` if the
+  /// symbol's byte positions don't fit `text`.
+  fn utf16_range(text: &str, symbol: &Symbol) -> Option<Range> {
+    let start = usize::try_from(symbol.offset).ok()?;
+    let line_start = start.checked_sub(usize::try_from(symbol.column - 1).ok()?)?;
+    let end_line_start = if symbol.end_line == symbol.line {
+      line_start
+    } else {
+      let newlines = usize::try_from(symbol.end_line - symbol.line).ok()?;
+
+      start + text.get(start..)?.match_indices('\n').nth(newlines - 1)?.0 + 1
+    };
+    let end = end_line_start + usize::try_from(symbol.end_column - 1).ok()?;
+
+    let position = |line: i64, line_start: usize, offset: usize| -> Option<Position> {
+      let column = text.get(line_start..offset)?.encode_utf16().count();
+
+      Position::new(u32::try_from(line - 1).ok()?, u32::try_from(column).ok()?).some()
+    };
+
+    Range::new(
+      position(symbol.line, line_start, start)?,
+      position(symbol.end_line, end_line_start, end)?,
+    )
+    .some()
+  }
+
+  fn symbol_kind(kind: Kind) -> SymbolKind {
+    match kind {
+      Kind::Module => SymbolKind::MODULE,
+      Kind::Macro | Kind::Alias | Kind::Function => SymbolKind::FUNCTION,
+      Kind::Global => SymbolKind::VARIABLE,
+      Kind::Constant | Kind::Define => SymbolKind::CONSTANT,
+      Kind::Class | Kind::Type => SymbolKind::CLASS,
+      Kind::Struct | Kind::Union => SymbolKind::STRUCT,
+      Kind::Enum => SymbolKind::ENUM,
+      Kind::Interface | Kind::Trait => SymbolKind::INTERFACE,
+      Kind::Method => SymbolKind::METHOD,
+      Kind::Impl => SymbolKind::OBJECT,
+      Kind::Field => SymbolKind::FIELD,
+      Kind::Variant => SymbolKind::ENUM_MEMBER,
+      Kind::Mode => SymbolKind::NAMESPACE,
+      Kind::Hook => SymbolKind::EVENT,
+    }
+  }
+}
+
+#[tower_lsp::async_trait]
+impl LanguageServer for Lsp {
+  async fn initialize(&self, params: InitializeParams) -> jsonrpc::Result<InitializeResult> {
+    let root = params
+      .root_uri
+      .and_then(|root_uri| root_uri.to_file_path().ok())
+      .unwrap_or_else(|| self.search_path.clone());
+    let root = root
+      .canonicalize()
+      .context("failed to canonicalize root")
+      .map_err(|err| jsonrpc::Error::invalid_params(format!("{err:#}")))?;
+    self.root.set(root).ok();
+
+    // symbols' columns are in bytes, so UTF-8 is preferred when available
+    let is_utf8 = params
+      .capabilities
+      .general
+      .and_then(|general| general.position_encodings)
+      .is_some_and(|position_encodings| position_encodings.contains(&PositionEncodingKind::UTF8));
+    self.is_utf8.set(is_utf8).ok();
+
+    InitializeResult {
+      capabilities: ServerCapabilities {
+        position_encoding: is_utf8.then_some(PositionEncodingKind::UTF8),
+        text_document_sync: TextDocumentSyncCapability::Options(TextDocumentSyncOptions {
+          open_close: true.some(),
+          change: TextDocumentSyncKind::FULL.some(),
+          save: TextDocumentSyncSaveOptions::SaveOptions(SaveOptions { include_text: true.some() }).some(),
+          ..TextDocumentSyncOptions::default()
+        })
+        .some(),
+        workspace_symbol_provider: OneOf::Left(true).some(),
+        document_symbol_provider: OneOf::Left(true).some(),
+        ..ServerCapabilities::default()
+      },
+      server_info: ServerInfo {
+        name: env!("CARGO_PKG_NAME").to_string(),
+        version: env!("CARGO_PKG_VERSION").to_string().some(),
+      }
+      .some(),
+    }
+    .ok()
+  }
+
+  async fn initialized(&self, _: InitializedParams) {
+    let Some(root) = self.root.get() else { return };
+
+    match self.index.index_path(root, self.concurrency).await {
+      Ok(errors) => {
+        for err in errors {
+          self.log_error(err).await;
+        }
+      }
+      Err(err) => self.log_error(err).await,
+    }
+  }
+
+  async fn shutdown(&self) -> jsonrpc::Result<()> {
+    ().ok()
+  }
+
+  async fn symbol(&self, params: WorkspaceSymbolParams) -> jsonrpc::Result<Option<Vec<SymbolInformation>>> {
+    let query = params.query.to_lowercase();
+    let matches: Vec<(PathBuf, Vec<Symbol>)> = self
+      .index
+      .files()
+      .await
+      .iter()
+      .filter_map(|(file_path, symbols)| {
+        let symbols: Vec<Symbol> = symbols
+          .iter()
+          .filter(|symbol| symbol.content.to_lowercase().contains(&query))
+          .cloned()
+          .collect();
+
+        (!symbols.is_empty()).then(|| (file_path.clone(), symbols))
+      })
+      .collect();
+
+    let mut symbol_informations = Vec::new();
+    for (file_path, symbols) in &matches {
+      symbol_informations.extend(self.symbol_informations(file_path, symbols).await);
+    }
+
+    symbol_informations.some().ok()
+  }
+
+  async fn document_symbol(&self, params: DocumentSymbolParams) -> jsonrpc::Result<Option<DocumentSymbolResponse>> {
+    let uri = params.text_document.uri;
+    let Ok(file_path) = uri.to_file_path() else { return None.ok() };
+
+    if !self.index.files().await.contains_key(&file_path) {
+      self.reindex(&uri, None).await;
+    }
+
+    let Some(symbols) = self.index.files().await.get(&file_path).cloned() else { return None.ok() };
+
+    self
+      .symbol_informations(&file_path, &symbols)
+      .await
+      .convert::<DocumentSymbolResponse>()
+      .some()
+      .ok()
+  }
+
+  async fn did_open(&self, params: DidOpenTextDocumentParams) {
+    self
+      .reindex(&params.text_document.uri, params.text_document.text.as_str().some())
+      .await;
+  }
+
+  async fn did_change(&self, params: DidChangeTextDocumentParams) {
+    // with full document sync, the last change holds the entire document
+    let Some(change) = params.content_changes.last() else { return };
+
+    self.reindex(&params.text_document.uri, change.text.as_str().some()).await;
+  }
+
+  async fn did_save(&self, params: DidSaveTextDocumentParams) {
+    self.reindex(&params.text_document.uri, params.text.as_deref()).await;
+  }
+
+  async fn did_close(&self, params: DidCloseTextDocumentParams) {
+    // discard any unsaved changes
+    self.reindex(&params.text_document.uri, None).await;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn symbol(line: i64, column: i64, offset: i64, end_line: i64, end_column: i64) -> Symbol {
+    Symbol {
+      offset,
+      end_line,
+      end_column,
+      ..Symbol::test(Kind::Function, "", "", line, column)
+    }
+  }
+
+  #[test]
+  fn utf16_range_counts_code_units() {
+    // "é" is 2 bytes and 1 UTF-16 code unit, "𝄞" is 4 bytes and 2
+    let text = "//\nlet é𝄞 = 1; fn f() {}\n";
+    let offset = text.find("f()").unwrap();
+    let column = offset - 3 + 1;
+    let f = symbol(2, column as i64, offset as i64, 2, column as i64 + 1);
+    assert_eq!(
+      Lsp::utf16_range(text, &f),
+      Range::new(Position::new(1, 16), Position::new(1, 17)).some()
+    );
+
+    let text = "é\né𝄞 fn\nx𝄞y\n";
+    let offset = text.find("fn").unwrap();
+    let spanning = symbol(2, 8, offset as i64, 3, 7);
+    assert_eq!(
+      Lsp::utf16_range(text, &spanning),
+      Range::new(Position::new(1, 4), Position::new(2, 4)).some()
+    );
+  }
+
+  #[test]
+  fn utf16_range_rejects_stale_positions() {
+    assert_eq!(Lsp::utf16_range("fn f() {}", &symbol(1, 4, 30, 1, 5)), None);
+    assert_eq!(Lsp::utf16_range("é", &symbol(1, 2, 1, 1, 3)), None);
+  }
+}
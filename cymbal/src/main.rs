@@ -1,49 +1,214 @@
 #![feature(lazy_cell_into_inner)]
 
 mod args;
+mod daemon;
+mod index;
+mod lsp;
+mod maintenance;
 mod walker;
+mod watcher;
 
-use anyhow::Result;
+use std::{io::Write, path::Path};
+
+use anyhow::{Context, Result};
 use clap::Parser;
-use cymbal::{cache, channel, config, ext, worker};
+use cymbal::{cache, channel, config, etags, event, ext, filter, format, parser, search, symbol, tags, worker};
+use futures::TryStreamExt;
 use tokio::task::JoinSet;
 
 use crate::{
-  args::Args,
+  args::{Args, Command},
+  daemon::Daemon,
+  event::Event,
   ext::{IntoExt, IteratorExt, Leak},
-  walker::Walker,
-  worker::Worker,
+  index::Index,
+  lsp::Lsp,
+  search::SearchTerm,
+  walker::{FileList, Walker},
+  watcher::Watcher,
+  worker::{Worker, WrittenSymbols},
 };
 
 #[tokio::main]
 async fn main() -> Result<()> {
   let args = Args::parse();
+
+  match args.command() {
+    Some(Command::Lsp) => lsp(&args).await,
+    Some(Command::Serve { socket }) => serve(&args, socket).await,
+    Some(Command::Search { term }) => search(&args, term).await,
+    Some(Command::Cache { command }) => maintenance::run(&args, command).await,
+    None if args.is_from_cache() => list_from_cache(&args).await,
+    None => list(&args).await,
+  }
+}
+
+async fn lsp(args: &Args) -> Result<()> {
+  let concurrency = args.concurrency()?.convert::<usize>();
+  let cache = args.cache().await?;
+  let config = args.config().await?.leak();
+  let walk_options = args.walk_options().leak();
+  Lsp::serve(config, cache, walk_options, concurrency, args.search_path().to_path_buf()).await;
+
+  ().ok()
+}
+
+async fn serve(args: &Args, socket_path: &Path) -> Result<()> {
+  let concurrency = args.concurrency()?.convert::<usize>();
+  let cache = args.cache().await?;
+  let config = args.config().await?.leak();
+  let format = args.format().await?;
+  let index = Index::new(config, cache, args.walk_options().leak()).leak();
+
+  Daemon::new(index, format, args.search_paths(), concurrency).run(socket_path).await
+}
+
+/// Lists all symbols under the search path, or of the files listed by
+/// `--files-from`, and with `--watch`, then writes symbol change events as
+/// files change.
+async fn list(args: &Args) -> Result<()> {
+  // the watcher is started first so that no changes made during the listing
+  // are missed
+  let search_paths = args.search_paths();
+  let walk_options = args.walk_options().leak();
+  let watcher = if args.watch() {
+    Watcher::new(&search_paths, walk_options)?.some()
+  } else {
+    None
+  };
   let available_concurrency = args.concurrency()?.convert::<usize>();
   let cache = args.cache().await?;
   let config = args.config().await?.leak();
   let (sender, receiver) = args.channel();
-  let delimiter = args.delimiter();
-  let separator = args.separator();
-  let color = args.color();
-  let should_clean_cache = !args.is_filtering();
-  let walker = Walker::new(args.search_path().to_path_buf(), sender, cache.clone(), should_clean_cache).spawn();
+  let format = args.format().await?;
+  let filter = args.filter()?.map(Leak::leak);
+  // the symbols listed are the baseline that changes are watched against
+  let written_symbols = watcher.is_some().then(|| WrittenSymbols::default().leak());
+  let walkers = match args.files_from() {
+    Some((files_from, separator)) => vec![FileList::new(files_from.to_path_buf(), separator, sender).spawn()],
+    None => {
+      // stale entries are cleaned under each search path that is walked in
+      // full, and the channel closes once every walker drops its sender
+      let walkers = search_paths
+        .iter()
+        .map(|search_path| {
+          let should_clean_cache = !args.is_filtering() && !search_path.is_file();
+          Walker::new(search_path.clone(), sender.clone(), cache.clone(), should_clean_cache, walk_options).spawn()
+        })
+        .collect();
+      drop(sender);
+
+      walkers
+    }
+  };
+
+  format.write_start(&mut std::io::stdout())?;
 
   let mut workers = JoinSet::new();
   for _ in 0..available_concurrency {
-    workers.spawn(
-      Worker::new(
-        cache.clone(),
-        config,
-        receiver.clone(),
-        delimiter,
-        separator,
-        color,
-        std::io::stdout(),
-      )
-      .run(),
-    );
+    let mut worker = Worker::new(cache.clone(), config, receiver.clone(), format, filter, std::io::stdout());
+    if let Some(written_symbols) = written_symbols {
+      worker = worker.keeping_written_symbols(written_symbols);
+    }
+    workers.spawn(worker.run());
   }
   workers.join_all().await.ok_all()?;
+  for walker in walkers {
+    walker.await??;
+  }
+
+  if let Some(cache) = &cache {
+    cache.flush().await?;
+  }
+
+  if let Some(filter) = filter {
+    filter.write_ranked(&format, &mut std::io::stdout())?;
+  }
+  format.write_end(&mut std::io::stdout()).await?;
 
-  walker.await?
+  if let Some(watcher) = watcher
+    && let Some(written_symbols) = written_symbols
+  {
+    let files = std::mem::take(&mut *written_symbols.lock().expect("poisoned written symbols lock"));
+
+    watch(Index::with_files(config, cache, walk_options, files), watcher).await?;
+  }
+
+  ().ok()
+}
+
+/// Lists the symbols cached under the search path without walking it.
+async fn list_from_cache(args: &Args) -> Result<()> {
+  let cache = args.read_cache().await?.context("--from-cache requires --cache")?;
+  let format = args.format().await?;
+  let filter = args.filter()?;
+  let languages = args.languages()?;
+  let kinds = args.kind_filter();
+  let mut stdout = std::io::stdout().lock();
+
+  format.write_start(&mut stdout)?;
+
+  let symbols = cache.symbols_under(&args.search_paths(), &languages, kinds.as_ref());
+  futures::pin_mut!(symbols);
+  while let Some((file_path, symbol)) = symbols.try_next().await? {
+    if filter.as_ref().is_none_or(|filter| filter.should_write(&file_path, &symbol)) {
+      format.write_symbol(&mut stdout, &file_path, &symbol)?;
+    }
+  }
+
+  if let Some(filter) = &filter {
+    filter.write_ranked(&format, &mut stdout)?;
+  }
+  format.write_end(&mut stdout).await
+}
+
+/// Writes the cached symbols under the search path matching `term`, from best
+/// to worst match.
+async fn search(args: &Args, term: &str) -> Result<()> {
+  const DEFAULT_LIMIT: usize = 100;
+
+  let cache = args.read_cache().await?.context("search requires --cache")?;
+  let format = args.format().await?;
+  let languages = args.languages()?;
+  let kinds = args.kind_filter();
+  let term = SearchTerm::new(term);
+  let limit = args.limit().unwrap_or(DEFAULT_LIMIT);
+  let mut stdout = std::io::stdout().lock();
+
+  format.write_start(&mut stdout)?;
+
+  let symbols = cache.search(&args.search_paths(), &term, args.match_context(), &languages, kinds.as_ref(), limit);
+  futures::pin_mut!(symbols);
+  while let Some((file_path, symbol)) = symbols.try_next().await? {
+    format.write_symbol(&mut stdout, &file_path, &symbol)?;
+  }
+
+  format.write_end(&mut stdout).await
+}
+
+/// Writes an [`Event`] for every symbol that changes in the files reported by
+/// `watcher`, against the symbols of each file in `index`, which are those
+/// just listed.
+///
+/// Errors for a single file or event are reported as warnings, so that only
+/// the watcher stopping or stdout failing ends the watch.
+async fn watch(index: Index, mut watcher: Watcher) -> Result<()> {
+  loop {
+    for file_path in watcher.changed_paths().await? {
+      let (previous, current) = match index.update(&file_path).await {
+        Ok(symbols) => symbols,
+        Err(err) => {
+          eprintln!("{err:#}");
+          continue;
+        }
+      };
+
+      let path = file_path.to_string_lossy();
+      let mut stdout = std::io::stdout().lock();
+      for event in Event::diff(&path, &previous, &current) {
+        event.write(&mut stdout)?;
+      }
+      stdout.flush().context("failed to flush events")?;
+    }
+  }
 }
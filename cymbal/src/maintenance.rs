@@ -0,0 +1,207 @@
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+use chrono::{TimeDelta, Utc};
+use clap::ValueEnum;
+use futures::StreamExt;
+
+use crate::{
+  args::{Args, CacheCommand},
+  cache::Cache,
+  config::{Config, Language},
+  ext::{IntoExt, Leak, TryStreamExt},
+  parser::Parser,
+  symbol::{FileInfo, Symbol},
+  worker::UniqueSymbolsExt,
+};
+
+/// Runs a `cache` subcommand.
+pub async fn run(args: &Args, command: &CacheCommand) -> Result<()> {
+  match command {
+    CacheCommand::Stats => stats(&args.existing_cache().await?).await,
+    CacheCommand::Gc => {
+      let deleted = args.existing_cache().await?.gc().await?;
+      println!("deleted {deleted} files");
+
+      ().ok()
+    }
+    CacheCommand::Clear => args.existing_cache().await?.clear().await,
+    CacheCommand::Verify { sample } => {
+      let config = args.config().await?;
+      if config.is_partial() {
+        bail!("cannot verify the cache while filtering kinds");
+      }
+
+      verify(&args.existing_cache().await?, config.leak(), *sample).await
+    }
+    CacheCommand::Vacuum => {
+      let (previous_size, size) = args.existing_cache().await?.vacuum().await?;
+      println!("reclaimed {} bytes, {size} bytes remain", previous_size.saturating_sub(size));
+
+      ().ok()
+    }
+    CacheCommand::List => list().await,
+    CacheCommand::Prune { older_than } => prune(*older_than).await,
+  }
+}
+
+async fn stats(cache: &Cache) -> Result<()> {
+  let stats = cache.stats().await?;
+
+  println!("path:    {}", stats.path.display());
+  println!("root:    {}", stats.root.display());
+  println!("size:    {} bytes", stats.size);
+  println!("files:   {}", stats.files);
+  println!("symbols: {}", stats.symbols);
+
+  for language_stats in &stats.languages {
+    println!();
+    println!(
+      "{}: {} files, {} symbols",
+      language_name(language_stats.language),
+      language_stats.files,
+      language_stats.symbols
+    );
+    for (kind, count) in &language_stats.kinds {
+      println!("  {:<8} {count}", kind.name());
+    }
+  }
+
+  ().ok()
+}
+
+async fn list() -> Result<()> {
+  for project_cache in Cache::project_caches().await? {
+    let root = match &project_cache.root {
+      Ok(Some(root)) if root.exists() => root.display().to_string(),
+      Ok(Some(root)) => format!("{} (missing)", root.display()),
+      Ok(None) => "unknown".to_string(),
+      Err(err) => format!("unknown ({err:#})"),
+    };
+
+    println!("{root}");
+    println!("  path:      {}", project_cache.path.display());
+    println!("  size:      {} bytes", project_cache.size);
+    println!("  last used: {}", project_cache.last_used.format("%Y-%m-%d %H:%M:%S UTC"));
+  }
+
+  ().ok()
+}
+
+/// Deletes the caches of projects that no longer exist, or that haven't been
+/// used in `older_than` days.
+///
+/// Caches whose root can't be read, such as those of a newer version of
+/// cymbal, are left as is.
+async fn prune(older_than: u32) -> Result<()> {
+  let cutoff = Utc::now() - TimeDelta::days(older_than.into());
+  let (mut deleted, mut reclaimed) = (0, 0);
+
+  for project_cache in Cache::project_caches().await? {
+    let reason = match &project_cache.root {
+      Ok(Some(root)) if !root.exists() => "project no longer exists",
+      Ok(_) if project_cache.last_used < cutoff => "not used recently",
+      Ok(_) => continue,
+      Err(err) => {
+        println!("{}: skipped, {err:#}", project_cache.path.display());
+        continue;
+      }
+    };
+
+    tokio::fs::remove_dir_all(&project_cache.path)
+      .await
+      .with_context(|| format!("failed to delete {}", project_cache.path.display()))?;
+    println!("{}: deleted, {reason}", project_cache.path.display());
+
+    deleted += 1;
+    reclaimed += project_cache.size;
+  }
+
+  println!("deleted {deleted} caches, reclaimed {reclaimed} bytes");
+
+  ().ok()
+}
+
+fn language_name(language: Language) -> String {
+  language
+    .to_possible_value()
+    .map_or_else(|| format!("{language:?}"), |value| value.get_name().to_string())
+}
+
+/// The result of reparsing a cached file.
+enum Verification {
+  UpToDate,
+  Stale(&'static str),
+  Mismatched { cached: usize, parsed: usize },
+  Skipped,
+}
+
+/// Reparses up to `sample` cached files and compares their symbols against
+/// the cached symbols, failing if any differ.
+async fn verify(cache: &Cache, config: &'static Config, sample: usize) -> Result<()> {
+  let (mut up_to_date, mut stale, mut mismatched, mut skipped) = (0, 0, 0, 0);
+
+  for (file_path, file_info) in cache.sample_files(sample).await? {
+    match verify_file(cache, config, &file_path, &file_info).await? {
+      Verification::UpToDate => up_to_date += 1,
+      Verification::Stale(reason) => {
+        println!("{}: stale, {reason}", file_path.display());
+        stale += 1;
+      }
+      Verification::Mismatched { cached, parsed } => {
+        println!(
+          "{}: {cached} cached symbols differ from {parsed} parsed symbols",
+          file_path.display()
+        );
+        mismatched += 1;
+      }
+      Verification::Skipped => skipped += 1,
+    }
+  }
+
+  println!("{up_to_date} up to date, {stale} stale, {mismatched} mismatched, {skipped} skipped");
+
+  if mismatched > 0 {
+    bail!("cached symbols of {mismatched} files differ from their parsed symbols");
+  }
+
+  ().ok()
+}
+
+async fn verify_file(cache: &Cache, config: &'static Config, file_path: &Path, file_info: &FileInfo) -> Result<Verification> {
+  let Ok(metadata) = file_path.metadata() else {
+    return Verification::Stale("file no longer exists").ok();
+  };
+  let Some(language) = Language::from_file_path(file_path).filter(|language| config.contains_language(*language)) else {
+    return Verification::Skipped.ok();
+  };
+
+  if file_info.modified != metadata.modified()?.convert::<chrono::DateTime<chrono::Utc>>() {
+    return Verification::Stale("modified since cached").ok();
+  }
+  if config.fingerprint(language) != file_info.fingerprint.as_str().some() {
+    return Verification::Stale("queries or grammar changed since cached").ok();
+  }
+
+  let mut cached: Vec<Symbol> = cache.get_symbols(file_path).filter_ok().collect().await;
+  let mut parsed: Vec<Symbol> = Parser::new(file_path, language, config)
+    .symbol_stream()
+    .await?
+    .unique_symbols()
+    .collect()
+    .await;
+
+  // symbols are unique by position
+  cached.sort_by_key(|symbol| (symbol.line, symbol.column));
+  parsed.sort_by_key(|symbol| (symbol.line, symbol.column));
+
+  if cached == parsed {
+    Verification::UpToDate.ok()
+  } else {
+    Verification::Mismatched {
+      cached: cached.len(),
+      parsed: parsed.len(),
+    }
+    .ok()
+  }
+}
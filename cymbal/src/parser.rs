@@ -1,4 +1,8 @@
-use std::path::Path;
+use std::{
+  cell::RefCell,
+  collections::{HashMap, hash_map::Entry},
+  path::Path,
+};
 
 use anyhow::{Context, Result};
 use futures::Stream;
@@ -11,6 +15,12 @@ use crate::{
   utils::Lazy,
 };
 
+thread_local! {
+  /// The parser and query cursor of each language on this thread, which are
+  /// reused across files instead of being created for each file.
+  static PARSERS: RefCell<HashMap<Language, (TreeSitterParser, QueryCursor)>> = RefCell::new(HashMap::new());
+}
+
 pub struct Parser<'a> {
   file_path: &'a Path,
   language: Language,
@@ -28,20 +38,56 @@ impl<'a> Parser<'a> {
     }
   }
 
-  pub async fn symbol_stream(self) -> Result<impl Stream<Item = Symbol>> {
-    let language = self.language;
-    let mut parser = TreeSitterParser::with_language(self.language)?;
+  pub async fn symbol_stream(self) -> Result<impl Stream<Item = Symbol> + use<>> {
     let content_bytes = self.file_path.read_bytes().await?;
-    let tree = parser.parse(&content_bytes, None).context("failed to create parser")?;
+
+    self.symbol_stream_from_bytes(content_bytes).await
+  }
+
+  /// Parses `content_bytes` as the content of the file, instead of reading the
+  /// file itself, such as for unsaved editor buffers.
+  ///
+  /// Parsing and matching queries is CPU-bound, so it runs on a blocking
+  /// thread rather than on the async runtime.
+  pub async fn symbol_stream_from_bytes(self, content_bytes: Vec<u8>) -> Result<impl Stream<Item = Symbol> + use<>> {
+    let (language, queries) = (self.language, self.queries);
+    let symbols = tokio::task::spawn_blocking(move || Self::parse(language, queries, &content_bytes))
+      .await
+      .context("failed to join parser thread")??;
+
+    symbols.into_iter().stream().ok()
+  }
+
+  /// Parses `content_bytes` with this thread's parser for `language`,
+  /// returning its symbols in the order of their queries in the config.
+  fn parse(language: Language, queries: Option<&'static Lazy<LanguageQuery>>, content_bytes: &[u8]) -> Result<Vec<Symbol>> {
+    PARSERS.with_borrow_mut(|parsers| {
+      let (parser, cursor) = match parsers.entry(language) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => entry.insert((TreeSitterParser::with_language(language)?, QueryCursor::new())),
+      };
+
+      Self::parse_with(language, queries, parser, cursor, content_bytes)
+    })
+  }
+
+  fn parse_with(
+    language: Language,
+    queries: Option<&'static Lazy<LanguageQuery>>,
+    parser: &mut TreeSitterParser,
+    cursor: &mut QueryCursor,
+    content_bytes: &[u8],
+  ) -> Result<Vec<Symbol>> {
+    let tree = parser.parse(content_bytes, None).context("failed to create parser")?;
 
     let mut symbols: Vec<(usize, Symbol)> = Vec::new();
 
-    if let Some(language_query) = self.queries {
+    if let Some(language_query) = queries {
       let language_query: &LanguageQuery = language_query;
       let symbol_index = language_query.symbol_index();
+      let definition_index = language_query.definition_index();
 
-      let mut cursor = QueryCursor::new();
-      let mut matches = cursor.matches(language_query.tree_sitter_query(), tree.root_node(), content_bytes.as_slice());
+      let mut matches = cursor.matches(language_query.tree_sitter_query(), tree.root_node(), content_bytes);
 
       while let Some(m) = StreamingIterator::next(&mut matches) {
         let meta = language_query.pattern(m.pattern_index);
@@ -50,12 +96,21 @@ impl<'a> Parser<'a> {
 
         let node = capture.node;
         let Point { row, column } = node.start_position();
+        let Point {
+          row: end_row,
+          column: end_column,
+        } = node.end_position();
+        let definition = m
+          .captures
+          .iter()
+          .find(|c| Some(c.index) == definition_index)
+          .map(|c| (c.node.start_position(), c.node.end_position()));
 
         let symbol_content_bytes = &content_bytes[node.start_byte()..node.end_byte()];
         let Some(symbol_content_str) = symbol_content_bytes.to_str() else { continue };
 
-        let leading = meta.leading().map(|t| t.render(m, content_bytes.as_slice())).and_then(Result::ok);
-        let trailing = meta.trailing().map(|t| t.render(m, content_bytes.as_slice())).and_then(Result::ok);
+        let leading = meta.leading().map(|t| t.render(m, content_bytes)).and_then(Result::ok);
+        let trailing = meta.trailing().map(|t| t.render(m, content_bytes)).and_then(Result::ok);
 
         #[allow(clippy::cast_possible_wrap)]
         symbols.push((
@@ -65,6 +120,13 @@ impl<'a> Parser<'a> {
             language,
             line: row as i64 + 1,
             column: column as i64 + 1,
+            offset: node.start_byte() as i64,
+            end_line: end_row as i64 + 1,
+            end_column: end_column as i64 + 1,
+            definition_line: definition.map(|(start, _)| start.row as i64 + 1),
+            definition_column: definition.map(|(start, _)| start.column as i64 + 1),
+            definition_end_line: definition.map(|(_, end)| end.row as i64 + 1),
+            definition_end_column: definition.map(|(_, end)| end.column as i64 + 1),
             content: symbol_content_str.to_string(),
             leading,
             trailing,
@@ -76,6 +138,6 @@ impl<'a> Parser<'a> {
     // (stable) sort by order of appearance in config
     symbols.sort_by_key(|(source_i, _)| *source_i);
 
-    symbols.into_iter().map(|(_, symbol)| symbol).stream().ok()
+    symbols.into_iter().map(|(_, symbol)| symbol).collect::<Vec<_>>().ok()
   }
 }
@@ -0,0 +1,114 @@
+use crate::ext::IntoExt;
+
+/// A term to search the full-text index of the cache for.
+///
+/// Symbols match if their content contains the term, or if their camelCase or
+/// snake_case segments start with the segments of the term, such that
+/// `fileSym` and `file_sym` both match `file_symbols` and `FileSymbols`.
+pub struct SearchTerm {
+  term: String,
+  segments: String,
+}
+
+impl SearchTerm {
+  /// The trigram index only matches substrings of at least this many
+  /// characters.
+  const MIN_INDEXED_LEN: usize = 3;
+
+  pub fn new(term: &str) -> Self {
+    Self {
+      term: term.to_string(),
+      segments: segments(term),
+    }
+  }
+
+  pub fn as_str(&self) -> &str {
+    &self.term
+  }
+
+  /// The fts5 query matching this term, if it is long enough to be matched by
+  /// the trigram index. The leading and trailing text of symbols is also
+  /// matched if `context` is set.
+  pub fn fts_query(&self, context: bool) -> Option<String> {
+    if self.term.chars().count() < Self::MIN_INDEXED_LEN {
+      return None;
+    }
+
+    let phrase = |text: &str| format!("\"{}\"", text.replace('"', "\"\""));
+    let mut query = format!("name : {}", phrase(&self.term));
+    if !self.segments.is_empty() {
+      query.push_str(&format!(" OR segments : {}", phrase(&self.segments)));
+    }
+    if context {
+      query.push_str(&format!(" OR context : {}", phrase(&self.term)));
+    }
+
+    query.some()
+  }
+
+  /// A `LIKE` pattern matching content that starts with this term.
+  pub fn prefix_pattern(&self) -> String {
+    format!("{}%", escape_like(&self.term))
+  }
+
+  /// A `LIKE` pattern matching segments that start with those of this term.
+  pub fn segments_pattern(&self) -> String {
+    format!("%{}%", escape_like(&self.segments))
+  }
+}
+
+/// The lowercase camelCase and snake_case segments of `text`, each preceded by
+/// a space, such as ` http server config` for `HTTPServer_config`.
+pub fn segments(text: &str) -> String {
+  let chars: Vec<char> = text.chars().collect();
+  let mut segments = String::with_capacity(text.len() + 8);
+  let mut is_in_segment = false;
+
+  for (index, &char) in chars.iter().enumerate() {
+    if !char.is_alphanumeric() {
+      is_in_segment = false;
+      continue;
+    }
+
+    let previous = index.checked_sub(1).map(|index| chars[index]);
+    let next = chars.get(index + 1);
+    let is_camel_case_start = char.is_uppercase() && previous.is_some_and(|previous| previous.is_lowercase() || previous.is_numeric());
+    // the last uppercase character of an acronym followed by a word, such as
+    // the `S` of `HTTPServer`
+    let is_acronym_end = char.is_uppercase() && previous.is_some_and(char::is_uppercase) && next.is_some_and(|next| next.is_lowercase());
+
+    if !is_in_segment || is_camel_case_start || is_acronym_end {
+      segments.push(' ');
+    }
+    segments.extend(char.to_lowercase());
+    is_in_segment = true;
+  }
+
+  segments
+}
+
+fn escape_like(text: &str) -> String {
+  text.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn segments_split_camel_and_snake_case() {
+    assert_eq!(segments("fileSymbols"), " file symbols");
+    assert_eq!(segments("HTTPServer_config"), " http server config");
+    assert_eq!(segments("utf8Decode"), " utf8 decode");
+    assert_eq!(segments("__init__"), " init");
+  }
+
+  #[test]
+  fn fts_query_needs_three_characters() {
+    assert_eq!(SearchTerm::new("fs").fts_query(false), None);
+    assert_eq!(
+      SearchTerm::new("fileSym").fts_query(true).as_deref(),
+      Some("name : \"fileSym\" OR segments : \" file sym\" OR context : \"fileSym\"")
+    );
+  }
+}
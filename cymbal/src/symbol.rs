@@ -1,32 +1,55 @@
+use std::collections::HashSet;
+
 use chrono::{DateTime, Utc};
+use clap::ValueEnum;
 use enum_assoc::Assoc;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use sqlx::Type as SqlxType;
 
 use crate::{
   color::{BLUE, CYAN, GREEN, MAGENTA, YELLOW},
   config::Language,
+  ext::IntoExt,
   utils::Colored,
 };
 
-#[derive(sqlx::FromRow)]
+#[derive(sqlx::FromRow, Clone)]
 pub struct FileInfo {
   pub modified: DateTime<Utc>,
   pub is_fully_parsed: bool,
+  /// The fingerprint of the language's queries the file was parsed with.
+  pub fingerprint: String,
+  /// The hash of the content of the file when it was parsed.
+  pub content_hash: Option<i64>,
 }
 
-#[derive(sqlx::FromRow, Debug)]
+#[derive(sqlx::FromRow, Clone, Debug, PartialEq, Eq, Serialize)]
 pub struct Symbol {
   pub kind: Kind,
   pub language: Language,
   pub line: i64,
   pub column: i64,
+  /// The byte offset of the symbol from the start of its file.
+  pub offset: i64,
+  /// The line of the end of the symbol.
+  pub end_line: i64,
+  /// The column just past the end of the symbol.
+  pub end_column: i64,
+  /// The start and end of the `@definition` capture enclosing the symbol, such
+  /// as the entire body of a function, if its query has one.
+  pub definition_line: Option<i64>,
+  pub definition_column: Option<i64>,
+  pub definition_end_line: Option<i64>,
+  pub definition_end_column: Option<i64>,
   pub content: String,
   pub leading: Option<String>,
   pub trailing: Option<String>,
 }
 
 impl Symbol {
+  /// Separators that end a `leading` template which renders a symbol's scope.
+  const SCOPE_SEPARATORS: &'static [&'static str] = &["::", ".", ":"];
+
   pub fn leading_str(&self) -> &str {
     self.leading.as_deref().unwrap_or("")
   }
@@ -34,58 +57,93 @@ impl Symbol {
   pub fn trailing_str(&self) -> &str {
     self.trailing.as_deref().unwrap_or("")
   }
+
+  /// The scope of this symbol, if its rendered `leading` text ends in a scope
+  /// separator, such as the `Circle` in `Circle::area`.
+  pub fn scope(&self) -> Option<&str> {
+    let scope = Self::SCOPE_SEPARATORS
+      .iter()
+      .find_map(|separator| self.leading_str().strip_suffix(separator))?;
+
+    if scope.is_empty() { None } else { scope.some() }
+  }
+
+  /// A Rust symbol for tests, with an empty `leading` meaning none and an
+  /// offset as if the symbol were on the first line.
+  #[doc(hidden)]
+  pub fn test(kind: Kind, leading: &str, content: &str, line: i64, column: i64) -> Self {
+    let content_len = i64::try_from(content.len()).expect("test symbol too long");
+
+    Self {
+      kind,
+      language: Language::Rust,
+      line,
+      column,
+      offset: column - 1,
+      end_line: line,
+      end_column: column + content_len,
+      definition_line: None,
+      definition_column: None,
+      definition_end_line: None,
+      definition_end_column: None,
+      content: content.to_string(),
+      leading: (!leading.is_empty()).then(|| leading.to_string()),
+      trailing: None,
+    }
+  }
 }
 
-#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Hash, Assoc, SqlxType)]
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Hash, Assoc, SqlxType, ValueEnum)]
 #[func(pub const fn color(&self) -> &'static str)]
 #[func(pub const fn to_str(&self) -> &'static str)]
+#[func(pub const fn name(&self) -> &'static str)]
 #[serde(rename_all = "lowercase")]
 #[repr(u8)]
 pub enum Kind {
-  #[assoc(to_str = "module ", color = YELLOW)]
+  #[assoc(to_str = "module ", name = "module", color = YELLOW)]
   Module,
-  #[assoc(to_str = "macro  ", color = YELLOW)]
+  #[assoc(to_str = "macro  ", name = "macro", color = YELLOW)]
   Macro,
-  #[assoc(to_str = "global ", color = YELLOW)]
+  #[assoc(to_str = "global ", name = "global", color = YELLOW)]
   Global,
-  #[assoc(to_str = "const  ", color = YELLOW)]
+  #[assoc(to_str = "const  ", name = "constant", color = YELLOW)]
   Constant,
-  #[assoc(to_str = "define ", color = YELLOW)]
+  #[assoc(to_str = "define ", name = "define", color = YELLOW)]
   Define,
 
-  #[assoc(to_str = "class  ", color = CYAN)]
+  #[assoc(to_str = "class  ", name = "class", color = CYAN)]
   Class,
-  #[assoc(to_str = "struct ", color = CYAN)]
+  #[assoc(to_str = "struct ", name = "struct", color = CYAN)]
   Struct,
-  #[assoc(to_str = "enum   ", color = CYAN)]
+  #[assoc(to_str = "enum   ", name = "enum", color = CYAN)]
   Enum,
-  #[assoc(to_str = "union  ", color = CYAN)]
+  #[assoc(to_str = "union  ", name = "union", color = CYAN)]
   Union,
 
-  #[assoc(to_str = "alias  ", color = BLUE)]
+  #[assoc(to_str = "alias  ", name = "alias", color = BLUE)]
   Alias,
-  #[assoc(to_str = "inter  ", color = BLUE)]
+  #[assoc(to_str = "inter  ", name = "interface", color = BLUE)]
   Interface,
-  #[assoc(to_str = "trait  ", color = BLUE)]
+  #[assoc(to_str = "trait  ", name = "trait", color = BLUE)]
   Trait,
-  #[assoc(to_str = "type   ", color = BLUE)]
+  #[assoc(to_str = "type   ", name = "type", color = BLUE)]
   Type,
 
-  #[assoc(to_str = "func   ", color = MAGENTA)]
+  #[assoc(to_str = "func   ", name = "function", color = MAGENTA)]
   Function,
-  #[assoc(to_str = "method ", color = MAGENTA)]
+  #[assoc(to_str = "method ", name = "method", color = MAGENTA)]
   Method,
-  #[assoc(to_str = "impl   ", color = MAGENTA)]
+  #[assoc(to_str = "impl   ", name = "impl", color = MAGENTA)]
   Impl,
-  #[assoc(to_str = "field  ", color = MAGENTA)]
+  #[assoc(to_str = "field  ", name = "field", color = MAGENTA)]
   Field,
 
-  #[assoc(to_str = "variant", color = GREEN)]
+  #[assoc(to_str = "variant", name = "variant", color = GREEN)]
   Variant,
 
-  #[assoc(to_str = "mode   ", color = BLUE)]
+  #[assoc(to_str = "mode   ", name = "mode", color = BLUE)]
   Mode,
-  #[assoc(to_str = "hook   ", color = GREEN)]
+  #[assoc(to_str = "hook   ", name = "hook", color = GREEN)]
   Hook,
 }
 
@@ -97,3 +155,63 @@ impl Kind {
     }
   }
 }
+
+/// The kinds of symbols to emit.
+#[derive(Clone)]
+pub struct KindFilter {
+  /// If empty, all kinds that are not excluded are included.
+  included: HashSet<Kind>,
+  excluded: HashSet<Kind>,
+}
+
+impl KindFilter {
+  pub fn new(included: HashSet<Kind>, excluded: HashSet<Kind>) -> Self {
+    Self { included, excluded }
+  }
+
+  pub fn contains(&self, kind: Kind) -> bool {
+    (self.included.is_empty() || self.included.contains(&kind)) && !self.excluded.contains(&kind)
+  }
+
+  pub fn included(&self) -> &HashSet<Kind> {
+    &self.included
+  }
+
+  pub fn excluded(&self) -> &HashSet<Kind> {
+    &self.excluded
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn scope_from_leading() {
+    let scope = |leading: &str| Symbol::test(Kind::Method, leading, "area", 1, 1).scope().map(str::to_string);
+
+    assert_eq!(scope("Circle::").as_deref(), Some("Circle"));
+    assert_eq!(scope("mod::Circle::").as_deref(), Some("mod::Circle"));
+    assert_eq!(scope("self.").as_deref(), Some("self"));
+    assert_eq!(scope("Display for "), None);
+    assert_eq!(scope(""), None);
+
+    let empty_leading = Symbol {
+      leading: String::new().some(),
+      ..Symbol::test(Kind::Method, "", "area", 1, 1)
+    };
+    assert_eq!(empty_leading.scope(), None);
+  }
+
+  #[test]
+  fn kind_filter_excludes_after_including() {
+    let types = KindFilter::new([Kind::Struct, Kind::Enum].into(), [Kind::Enum].into());
+    assert!(types.contains(Kind::Struct));
+    assert!(!types.contains(Kind::Enum));
+    assert!(!types.contains(Kind::Field));
+
+    let no_fields = KindFilter::new(HashSet::new(), [Kind::Field].into());
+    assert!(no_fields.contains(Kind::Method));
+    assert!(!no_fields.contains(Kind::Field));
+  }
+}
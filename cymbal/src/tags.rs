@@ -0,0 +1,280 @@
+use std::{
+  collections::HashMap,
+  fs::File,
+  path::{Path, PathBuf},
+  sync::Mutex,
+  time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+
+use crate::{ext::IntoExt, symbol::Symbol};
+
+/// A universal-ctags compatible `tags` file.
+///
+/// Entries are collected from every worker and are only sorted and written
+/// once all files have been processed.
+pub struct Tags {
+  path: PathBuf,
+  /// Entries of the `tags` file at `path` when cymbal started, keyed by the
+  /// file they point to. These are reused for files that have not changed
+  /// since the previous `tags` file was generated.
+  previous: HashMap<PathBuf, Vec<String>>,
+  /// When the previous `tags` file was generated, which is its mtime.
+  previous_started: Option<DateTime<Utc>>,
+  /// The [`Config::languages_fingerprint`] of the symbols of this `tags` file,
+  /// which previous entries are only reused if they share.
+  ///
+  /// [`Config::languages_fingerprint`]: crate::config::Config::languages_fingerprint
+  fingerprint: String,
+  /// When this `tags` file started being generated, before any file was read,
+  /// which is set as its mtime once written.
+  started: SystemTime,
+  entries: Mutex<Vec<String>>,
+}
+
+impl Tags {
+  const HEADERS: &'static [(&'static str, &'static str, &'static str)] = &[
+    ("!_TAG_FILE_FORMAT", "2", "extended format; --format=1 will not append ;\" to lines"),
+    ("!_TAG_FILE_SORTED", "1", "0=unsorted, 1=sorted, 2=foldcase"),
+    ("!_TAG_PROGRAM_NAME", "cymbal", ""),
+    ("!_TAG_PROGRAM_URL", env!("CARGO_PKG_REPOSITORY"), ""),
+    ("!_TAG_PROGRAM_VERSION", env!("CARGO_PKG_VERSION"), ""),
+  ];
+
+  /// The pseudo-tag holding the fingerprint of the symbols of a `tags` file.
+  const FINGERPRINT_TAG: &'static str = "!_TAG_CYMBAL_FINGERPRINT";
+
+  /// Creates an empty tags file at `path`, of symbols parsed with
+  /// `fingerprint`.
+  ///
+  /// If `incremental` is set, entries of an existing `tags` file at `path` are
+  /// loaded so they can be reused for unchanged files, unless its symbols
+  /// were parsed with another fingerprint.
+  pub async fn new(path: PathBuf, incremental: bool, fingerprint: String) -> Result<Self> {
+    let started = SystemTime::now();
+    let (previous, previous_started) = if incremental && path.exists() {
+      let metadata = tokio::fs::metadata(&path).await.context("failed to read tags file metadata")?;
+      let content = tokio::fs::read_to_string(&path).await.context("failed to read tags file")?;
+
+      if Self::parse_fingerprint(&content) == Some(&fingerprint) {
+        (Self::parse_entries(&content), metadata.modified().ok().map(DateTime::from))
+      } else {
+        (HashMap::new(), None)
+      }
+    } else {
+      (HashMap::new(), None)
+    };
+
+    Self {
+      path,
+      previous,
+      previous_started,
+      fingerprint,
+      started,
+      entries: Mutex::new(Vec::new()),
+    }
+    .ok()
+  }
+
+  fn parse_fingerprint(content: &str) -> Option<&str> {
+    content
+      .lines()
+      .take_while(|line| line.starts_with("!_TAG_"))
+      .find_map(|line| line.strip_prefix(Self::FINGERPRINT_TAG)?.strip_prefix('\t')?.split('\t').next())
+  }
+
+  fn parse_entries(content: &str) -> HashMap<PathBuf, Vec<String>> {
+    let mut entries = HashMap::<PathBuf, Vec<String>>::new();
+
+    for line in content.lines().filter(|line| !line.starts_with("!_TAG_")) {
+      let Some(file_path) = line.split('\t').nth(1) else { continue };
+
+      entries.entry(PathBuf::from(file_path)).or_default().push(line.to_string());
+    }
+
+    entries
+  }
+
+  /// Reuses the previous entries for `file_path`, returning whether any
+  /// existed and the file was last modified before they were generated.
+  pub fn reuse_previous(&self, file_path: &Path, file_modified: &DateTime<Utc>) -> bool {
+    let Some(previous_started) = &self.previous_started else { return false };
+    if file_modified >= previous_started {
+      return false;
+    }
+    let Some(previous) = self.previous.get(file_path) else { return false };
+
+    self.entries.lock().expect("poisoned tags lock").extend(previous.iter().cloned());
+
+    true
+  }
+
+  pub fn push(&self, file_path: &Path, symbol: &Symbol) {
+    // tag names and file names cannot contain tabs or newlines
+    let path = file_path.to_string_lossy();
+    if [symbol.content.as_str(), &path].iter().any(|s| s.contains(['\t', '\n'])) {
+      return;
+    }
+
+    let mut entry = format!(
+      "{name}\t{path}\t{line};\"\tkind:{kind}\tline:{line}",
+      name = symbol.content,
+      line = symbol.line,
+      kind = symbol.kind.name(),
+    );
+
+    if let Some(scope) = symbol.scope()
+      && !scope.contains(['\t', '\n'])
+    {
+      entry.push_str("\tscope:");
+      entry.push_str(scope);
+    }
+
+    if let Some(end_line) = symbol.definition_end_line {
+      entry.push_str(&format!("\tend:{end_line}"));
+    }
+
+    self.entries.lock().expect("poisoned tags lock").push(entry);
+  }
+
+  /// Sorts all entries and writes them, along with the headers, to the tags
+  /// file.
+  ///
+  /// The file's mtime is set to when it started being generated, so that the
+  /// entries of files modified since are not reused by the next run.
+  pub fn write(&self) -> Result<()> {
+    let mut entries = self.entries.lock().expect("poisoned tags lock");
+    entries.sort_unstable();
+    entries.dedup();
+
+    let mut content = String::new();
+    for (name, value, comment) in Self::HEADERS {
+      content.push_str(&format!("{name}\t{value}\t/{comment}/\n"));
+    }
+    content.push_str(&format!(
+      "{}\t{}\t/queries, grammars, and version symbols were parsed with/\n",
+      Self::FINGERPRINT_TAG,
+      self.fingerprint
+    ));
+    for entry in entries.iter() {
+      content.push_str(entry);
+      content.push('\n');
+    }
+
+    std::fs::write(&self.path, content).context("failed to write tags file")?;
+
+    File::options()
+      .write(true)
+      .open(&self.path)
+      .and_then(|file| file.set_modified(self.started))
+      .context("failed to set tags file mtime")
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use chrono::TimeDelta;
+
+  use super::*;
+  use crate::symbol::Kind;
+
+  fn method() -> Symbol {
+    Symbol {
+      definition_line: 3.some(),
+      definition_column: 3.some(),
+      definition_end_line: 5.some(),
+      definition_end_column: 4.some(),
+      ..Symbol::test(Kind::Method, "Circle::", "area", 3, 6)
+    }
+  }
+
+  fn entries(path: &Path) -> Vec<String> {
+    std::fs::read_to_string(path)
+      .unwrap()
+      .lines()
+      .filter(|line| !line.starts_with("!_TAG_"))
+      .map(str::to_string)
+      .collect()
+  }
+
+  #[tokio::test]
+  async fn entries_are_sorted_with_extension_fields() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("tags");
+    let tags = Tags::new(path.clone(), false, "fingerprint".to_string()).await.unwrap();
+    tags.push(Path::new("src/shapes.rs"), &method());
+    tags.push(
+      Path::new("src/main.rs"),
+      &Symbol {
+        kind: Kind::Function,
+        line: 1,
+        leading: None,
+        definition_end_line: None,
+        content: "main".to_string(),
+        ..method()
+      },
+    );
+    tags.write().unwrap();
+
+    let content = std::fs::read_to_string(&path).unwrap();
+    assert!(content.starts_with("!_TAG_FILE_FORMAT\t2\t/extended format; --format=1 will not append ;\" to lines/\n"));
+    assert_eq!(
+      entries(&path),
+      [
+        "area\tsrc/shapes.rs\t3;\"\tkind:method\tline:3\tscope:Circle\tend:5",
+        "main\tsrc/main.rs\t1;\"\tkind:function\tline:1",
+      ],
+    );
+  }
+
+  #[tokio::test]
+  async fn entries_are_reused_for_files_unmodified_since_written() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("tags");
+    let tags = Tags::new(path.clone(), false, "fingerprint".to_string()).await.unwrap();
+    tags.push(Path::new("src/shapes.rs"), &method());
+    tags.write().unwrap();
+
+    let written = DateTime::from(std::fs::metadata(&path).unwrap().modified().unwrap());
+    let before = written - TimeDelta::seconds(1);
+
+    let tags = Tags::new(path.clone(), true, "fingerprint".to_string()).await.unwrap();
+    assert!(tags.reuse_previous(Path::new("src/shapes.rs"), &before));
+    assert!(!tags.reuse_previous(Path::new("src/main.rs"), &before));
+    tags.write().unwrap();
+    assert_eq!(
+      entries(&path),
+      ["area\tsrc/shapes.rs\t3;\"\tkind:method\tline:3\tscope:Circle\tend:5"]
+    );
+
+    // a file modified since the tags file was written is parsed again, even
+    // if it is cached
+    let tags = Tags::new(path.clone(), true, "fingerprint".to_string()).await.unwrap();
+    assert!(!tags.reuse_previous(Path::new("src/shapes.rs"), &Utc::now()));
+    tags.write().unwrap();
+    assert!(entries(&path).is_empty());
+  }
+
+  #[tokio::test]
+  async fn entries_are_not_reused_with_another_fingerprint() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("tags");
+    let tags = Tags::new(path.clone(), false, "fingerprint".to_string()).await.unwrap();
+    tags.push(Path::new("src/shapes.rs"), &method());
+    tags.write().unwrap();
+    assert!(
+      std::fs::read_to_string(&path)
+        .unwrap()
+        .contains("\n!_TAG_CYMBAL_FINGERPRINT\tfingerprint\t")
+    );
+
+    let written = DateTime::from(std::fs::metadata(&path).unwrap().modified().unwrap());
+    let before = written - TimeDelta::seconds(1);
+
+    // such as after the queries, a grammar, or cymbal itself changed
+    let tags = Tags::new(path.clone(), true, "other".to_string()).await.unwrap();
+    assert!(!tags.reuse_previous(Path::new("src/shapes.rs"), &before));
+  }
+}
@@ -1,8 +1,20 @@
-use std::{collections::HashSet, path::PathBuf};
+use std::{
+  collections::HashSet,
+  ffi::OsString,
+  os::unix::ffi::OsStringExt,
+  path::{Path, PathBuf},
+  sync::Mutex,
+};
 
-use anyhow::Result;
-use ignore::Walk;
-use tokio::task::JoinHandle;
+use anyhow::{Context, Result, bail};
+use ignore::{
+  DirEntry, ParallelVisitor, ParallelVisitorBuilder, WalkBuilder, WalkState,
+  overrides::{Override, OverrideBuilder},
+};
+use tokio::{
+  io::{AsyncBufReadExt, AsyncRead, BufReader},
+  task::JoinHandle,
+};
 
 use crate::{
   cache::Cache,
@@ -11,20 +23,87 @@ use crate::{
   ext::IntoExt,
 };
 
+/// Which files are walked, beyond those in a supported language.
+///
+/// By default, hidden files and files ignored by `.gitignore`, `.ignore`, or
+/// `.cymbalignore` files are skipped, and symbolic links are not followed.
+#[derive(Clone, Default)]
+pub struct WalkOptions {
+  /// Whether hidden files and directories are walked.
+  pub hidden: bool,
+  /// Whether every ignore file is disregarded.
+  pub no_ignore: bool,
+  /// Whether git's ignore files are disregarded.
+  pub no_ignore_vcs: bool,
+  pub follow_links: bool,
+  /// How many directories below the search path are walked.
+  pub max_depth: Option<usize>,
+  /// Globs that files must match to be walked, in `.gitignore` syntax.
+  pub globs: Vec<String>,
+  /// Globs of files and directories to skip, in `.gitignore` syntax.
+  pub excludes: Vec<String>,
+}
+
+impl WalkOptions {
+  /// The name of the ignore files specific to cymbal, which take precedence
+  /// over `.gitignore` and `.ignore` files.
+  pub const IGNORE_FILE_NAME: &'static str = ".cymbalignore";
+
+  /// The builder of a walk of `path` with these options.
+  pub fn builder(&self, path: &Path) -> Result<WalkBuilder> {
+    let mut builder = WalkBuilder::new(path);
+    let git_ignore = !self.no_ignore && !self.no_ignore_vcs;
+    builder
+      .hidden(!self.hidden)
+      .parents(!self.no_ignore)
+      .ignore(!self.no_ignore)
+      .git_ignore(git_ignore)
+      .git_global(git_ignore)
+      .git_exclude(git_ignore)
+      .follow_links(self.follow_links)
+      .max_depth(self.max_depth)
+      .overrides(self.overrides(path)?);
+
+    if !self.no_ignore {
+      builder.add_custom_ignore_filename(Self::IGNORE_FILE_NAME);
+    }
+
+    builder.ok()
+  }
+
+  /// The matcher of `globs` and `excludes` for paths under `root`.
+  fn overrides(&self, root: &Path) -> Result<Override> {
+    let mut builder = OverrideBuilder::new(root);
+    for glob in &self.globs {
+      builder.add(glob).with_context(|| format!("invalid glob: {glob}"))?;
+    }
+    // overrides exclude files with negated globs
+    for exclude in &self.excludes {
+      builder
+        .add(&format!("!{exclude}"))
+        .with_context(|| format!("invalid glob: {exclude}"))?;
+    }
+
+    builder.build().context("failed to build globs")
+  }
+}
+
 pub struct Walker {
   path: PathBuf,
   sender: Sender,
   cache: Option<Cache>,
   should_clean_cache: bool,
+  options: &'static WalkOptions,
 }
 
 impl Walker {
-  pub fn new(path: PathBuf, sender: Sender, cache: Option<Cache>, should_clean_cache: bool) -> Self {
+  pub fn new(path: PathBuf, sender: Sender, cache: Option<Cache>, should_clean_cache: bool, options: &'static WalkOptions) -> Self {
     Self {
       path,
       sender,
       cache,
       should_clean_cache,
+      options,
     }
   }
 
@@ -33,33 +112,173 @@ impl Walker {
   }
 
   async fn run(self) -> Result<()> {
-    let walker = Walk::new(self.path).filter_map(Result::ok).filter_map(|dir_entry| {
-      let metadata = dir_entry.metadata().ok()?;
-      if !metadata.is_file() {
-        return None;
-      }
-      let file_modified = metadata.modified().ok()?;
-      let file_path = dir_entry.into_path();
-      let language = Language::from_file_path(&file_path)?;
+    let builder = self.options.builder(&self.path)?;
+    let sender = self.sender.clone();
+    // files cached after the walk starts are never stale
+    let generation = match &self.cache {
+      Some(cache) if self.should_clean_cache => cache.generation().await?.some(),
+      _ => None,
+    };
+    let file_paths = tokio::task::spawn_blocking(move || Self::walk(&builder, &sender)).await??;
 
-      (file_path, file_modified, language).some()
+    if let Some(cache) = &self.cache
+      && let Some(generation) = generation
+    {
+      cache.delete_stale_file_paths(&self.path, &file_paths, generation).await?;
+    }
+
+    ().ok()
+  }
+
+  /// Walks the path of `builder` on multiple threads, sending a task for
+  /// every file in a supported language as soon as it is found, and returning
+  /// the paths of those files.
+  fn walk(builder: &WalkBuilder, sender: &Sender) -> Result<HashSet<PathBuf>> {
+    let file_paths = Mutex::new(HashSet::new());
+
+    builder.build_parallel().visit(&mut VisitorBuilder {
+      sender,
+      file_paths: &file_paths,
     });
 
-    let mut file_paths = HashSet::new();
+    // the channel is only closed if every worker stopped early
+    if sender.is_closed() {
+      bail!("failed to send files to parse, as every worker stopped");
+    }
 
-    for (file_path, file_modified, language) in walker {
-      let file_task = FileTask::new(file_path.clone(), file_modified.into(), language);
+    file_paths.into_inner().expect("poisoned file paths lock").ok()
+  }
+}
+
+/// Sends a task for every file listed in a file instead of walking a
+/// directory, such as the output of `git ls-files`.
+pub struct FileList {
+  /// The file listing the paths, or `-` for stdin.
+  path: PathBuf,
+  /// The byte that separates the listed paths.
+  separator: u8,
+  sender: Sender,
+}
+
+impl FileList {
+  pub fn new(path: PathBuf, separator: u8, sender: Sender) -> Self {
+    Self { path, separator, sender }
+  }
+
+  pub fn spawn(self) -> JoinHandle<Result<()>> {
+    tokio::spawn(self.run())
+  }
 
-      self.sender.send(file_task).await?;
-      file_paths.insert(file_path.clone());
+  async fn run(self) -> Result<()> {
+    if self.path.as_os_str() == "-" {
+      return self.send_file_tasks(tokio::io::stdin()).await;
     }
 
-    if let Some(cache) = &self.cache
-      && self.should_clean_cache
-    {
-      cache.delete_stale_file_paths(&file_paths).await?;
+    let file = tokio::fs::File::open(&self.path)
+      .await
+      .with_context(|| format!("failed to open {}", self.path.display()))?;
+
+    self.send_file_tasks(file).await
+  }
+
+  /// Sends a task for every listed file that exists and is in a supported
+  /// language, skipping empty and repeated paths.
+  async fn send_file_tasks(&self, reader: impl AsyncRead + Unpin) -> Result<()> {
+    let mut reader = BufReader::new(reader);
+    let mut file_paths = HashSet::new();
+    let mut line = Vec::new();
+
+    loop {
+      line.clear();
+      let read = reader
+        .read_until(self.separator, &mut line)
+        .await
+        .context("failed to read file list")?;
+      if read == 0 {
+        break;
+      }
+
+      let mut path_bytes = line.strip_suffix(&[self.separator]).unwrap_or(&line);
+      if self.separator == b'\n' {
+        path_bytes = path_bytes.strip_suffix(b"\r").unwrap_or(path_bytes);
+      }
+      if path_bytes.is_empty() {
+        continue;
+      }
+
+      let file_path = PathBuf::from(OsString::from_vec(path_bytes.to_vec()));
+      if !file_paths.insert(file_path.clone()) {
+        continue;
+      }
+
+      if let Some(file_task) = FileTask::from_file_path(file_path) {
+        self.sender.send(file_task).await?;
+      }
     }
 
     ().ok()
   }
 }
+
+struct VisitorBuilder<'a> {
+  sender: &'a Sender,
+  file_paths: &'a Mutex<HashSet<PathBuf>>,
+}
+
+impl<'a> ParallelVisitorBuilder<'a> for VisitorBuilder<'a> {
+  fn build(&mut self) -> Box<dyn ParallelVisitor + 'a> {
+    Box::new(Visitor {
+      sender: self.sender,
+      file_paths: HashSet::new(),
+      all_file_paths: self.file_paths,
+    })
+  }
+}
+
+/// Visits the entries of a single walker thread, collecting the paths of its
+/// files separately so that threads don't contend for them.
+struct Visitor<'a> {
+  sender: &'a Sender,
+  file_paths: HashSet<PathBuf>,
+  all_file_paths: &'a Mutex<HashSet<PathBuf>>,
+}
+
+impl Visitor<'_> {
+  fn file_task(dir_entry: DirEntry) -> Option<FileTask> {
+    let metadata = dir_entry.metadata().ok()?;
+    if !metadata.is_file() {
+      return None;
+    }
+    let file_modified = metadata.modified().ok()?;
+    let file_path = dir_entry.into_path();
+    let language = Language::from_file_path(&file_path)?;
+
+    FileTask::new(file_path, file_modified.into(), language).some()
+  }
+}
+
+impl ParallelVisitor for Visitor<'_> {
+  fn visit(&mut self, dir_entry: Result<DirEntry, ignore::Error>) -> WalkState {
+    let Some(file_task) = dir_entry.ok().and_then(Self::file_task) else {
+      return WalkState::Continue;
+    };
+    let file_path = file_task.file_path.clone();
+
+    if self.sender.send_blocking(file_task).is_err() {
+      return WalkState::Quit;
+    }
+    self.file_paths.insert(file_path);
+
+    WalkState::Continue
+  }
+}
+
+impl Drop for Visitor<'_> {
+  fn drop(&mut self) {
+    self
+      .all_file_paths
+      .lock()
+      .expect("poisoned file paths lock")
+      .extend(self.file_paths.drain());
+  }
+}
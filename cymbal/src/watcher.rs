@@ -0,0 +1,178 @@
+use std::{
+  collections::HashSet,
+  path::{Path, PathBuf},
+  time::Duration,
+};
+
+use anyhow::{Context, Result};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher as _};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+use crate::{
+  ext::{Ignore, IntoExt},
+  walker::WalkOptions,
+};
+
+/// Watches directories for changes to the files that a
+/// [`Walker`](crate::walker::Walker) with the same [`WalkOptions`] walks.
+pub struct Watcher {
+  roots: Vec<Root>,
+  options: &'static WalkOptions,
+  receiver: UnboundedReceiver<notify::Result<Event>>,
+  // events stop being sent once the watcher is dropped
+  _watcher: RecommendedWatcher,
+}
+
+/// A watched search path.
+struct Root {
+  path: PathBuf,
+  /// The absolute form of `path`, which prefixes the paths of its events.
+  absolute_path: PathBuf,
+}
+
+impl Watcher {
+  /// How long to wait for further events before reporting a batch of changes.
+  const DEBOUNCE: Duration = Duration::from_millis(100);
+
+  /// Watches every path of `roots`, none of which should be under another.
+  pub fn new(roots: &[PathBuf], options: &'static WalkOptions) -> Result<Self> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event| sender.send(event).ignore()).context("failed to create watcher")?;
+    for root in roots {
+      watcher
+        .watch(root, RecursiveMode::Recursive)
+        .with_context(|| format!("failed to watch search path {}", root.display()))?;
+    }
+
+    Self {
+      roots: roots.iter().map(|root| Root::new(root)).collect::<Result<_>>()?,
+      options,
+      receiver,
+      _watcher: watcher,
+    }
+    .ok()
+  }
+
+  /// Waits for the next batch of created, modified, or removed file paths.
+  ///
+  /// Paths are reported in the same form as their watched root, so a root of
+  /// `.` results in paths like `./src/main.rs`.
+  ///
+  /// Errors for individual events are reported rather than returned, so this
+  /// only fails once the watcher has stopped.
+  pub async fn changed_paths(&mut self) -> Result<HashSet<PathBuf>> {
+    let mut changed_paths = vec![HashSet::new(); self.roots.len()];
+
+    let event = self.receiver.recv().await.context("watcher stopped")?;
+    self.insert_event_paths(&mut changed_paths, event);
+
+    while let Ok(Some(event)) = tokio::time::timeout(Self::DEBOUNCE, self.receiver.recv()).await {
+      self.insert_event_paths(&mut changed_paths, event);
+    }
+
+    let mut file_paths = HashSet::new();
+    for (root, changed_paths) in self.roots.iter().zip(changed_paths) {
+      if changed_paths.is_empty() {
+        continue;
+      }
+
+      // such as if a changed directory was removed while being walked, which
+      // later events report anyway
+      match root.walked_paths(changed_paths, self.options).await {
+        Ok(walked_paths) => file_paths.extend(walked_paths),
+        Err(err) => eprintln!("{err:#}"),
+      }
+    }
+
+    file_paths.ok()
+  }
+
+  /// Inserts the paths of `event` into the changed paths of their root.
+  ///
+  /// An error event, such as for a directory that could not be watched, is
+  /// reported without stopping the watcher, as later events may still arrive.
+  fn insert_event_paths(&self, changed_paths: &mut [HashSet<PathBuf>], event: notify::Result<Event>) {
+    let event = match event.context("failed to watch") {
+      Ok(event) => event,
+      Err(err) => {
+        eprintln!("{err:#}");
+
+        return;
+      }
+    };
+    if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)) {
+      return;
+    }
+
+    for path in event.paths {
+      let Some((index, root, relative_path)) = self.roots.iter().enumerate().find_map(|(index, root)| {
+        path
+          .strip_prefix(&root.absolute_path)
+          .ok()
+          .map(|relative_path| (index, root, relative_path))
+      }) else {
+        continue;
+      };
+
+      if path.is_dir() {
+        continue;
+      }
+
+      // the root itself changed if it is a file
+      if relative_path.as_os_str().is_empty() {
+        changed_paths[index].insert(root.path.clone());
+      } else {
+        changed_paths[index].insert(root.path.join(relative_path));
+      }
+    }
+  }
+}
+
+impl Root {
+  fn new(path: &Path) -> Result<Self> {
+    Self {
+      path: path.to_path_buf(),
+      absolute_path: std::path::absolute(path).context("failed to get absolute search path")?,
+    }
+    .ok()
+  }
+
+  /// Those of `changed_paths` under this root that are walked with `options`,
+  /// along with those that no longer exist, as removing a file that wasn't
+  /// walked changes nothing.
+  ///
+  /// Rather than duplicating the walker's rules for hidden and ignored files,
+  /// the root is walked with them, but only into the directories containing
+  /// changed paths.
+  async fn walked_paths(&self, changed_paths: HashSet<PathBuf>, options: &WalkOptions) -> Result<HashSet<PathBuf>> {
+    let (mut file_paths, existing_paths): (HashSet<_>, HashSet<_>) = changed_paths.into_iter().partition(|path| !path.exists());
+    if existing_paths.contains(&self.path) {
+      file_paths.insert(self.path.clone());
+
+      return file_paths.ok();
+    }
+
+    let ancestors: HashSet<PathBuf> = existing_paths
+      .iter()
+      .flat_map(|path| path.ancestors().skip(1).take_while(|ancestor| ancestor.starts_with(&self.path)))
+      .map(Path::to_path_buf)
+      .collect();
+
+    let mut builder = options.builder(&self.path)?;
+    builder.filter_entry(move |entry| existing_paths.contains(entry.path()) || ancestors.contains(entry.path()));
+
+    let walked_paths = tokio::task::spawn_blocking(move || {
+      builder
+        .build()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_some_and(|file_type| !file_type.is_dir()))
+        .map(ignore::DirEntry::into_path)
+        .collect::<Vec<_>>()
+    })
+    .await
+    .context("failed to walk changed paths")?;
+    file_paths.extend(walked_paths);
+
+    file_paths.ok()
+  }
+}
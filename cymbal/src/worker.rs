@@ -1,49 +1,80 @@
-use std::{collections::HashSet, io::Write, path::Path};
+use std::{
+  collections::{HashMap, HashSet},
+  io::Write,
+  path::{Path, PathBuf},
+  sync::Mutex,
+};
 
-use anyhow::{Context, Result};
+use anyhow::Result;
 use chrono::{DateTime, Utc};
 use futures::{Stream, StreamExt};
 
 use crate::{
-  cache::Cache,
+  cache::{Cache, Content, Lookup},
   channel::{FileTask, Receiver},
   config::Config,
-  ext::{IntoExt, TryStreamExt},
+  ext::{Ignore, IntoExt},
+  filter::Filter,
+  format::Format,
   parser::Parser,
   symbol::Symbol,
 };
 
+/// The symbols written for each file with any, shared by every worker.
+pub type WrittenSymbols = Mutex<HashMap<PathBuf, Vec<Symbol>>>;
+
 pub struct Worker<W: Write> {
   cache: Option<Cache>,
   config: &'static Config,
   receiver: Receiver,
-  delimiter: char,
-  separator: char,
-  color: bool,
+  format: Format,
+  filter: Option<&'static Filter>,
   writer: W,
+  /// Files whose cached symbols are up to date, which are written in batches
+  /// so that their symbols are read with a single query.
+  cache_hits: Vec<PathBuf>,
+  /// Whether any of the cache hits were copied from an identical file, and so
+  /// must be flushed before their symbols are read.
+  has_copied_hits: bool,
+  /// Where the symbols written for each file are kept, if anywhere.
+  written_symbols: Option<&'static WrittenSymbols>,
 }
 
 impl<W: Write> Worker<W> {
+  /// The number of cache hits whose symbols are read at once.
+  const CACHE_HITS_BATCH_SIZE: usize = 256;
+
   pub fn new(
     cache: Option<Cache>,
     config: &'static Config,
     receiver: Receiver,
-    delimiter: char,
-    separator: char,
-    color: bool,
+    format: Format,
+    filter: Option<&'static Filter>,
     writer: W,
   ) -> Self {
     Self {
       cache,
       config,
       receiver,
-      delimiter,
-      color,
-      separator,
+      format,
+      filter,
       writer,
+      cache_hits: Vec::new(),
+      has_copied_hits: false,
+      written_symbols: None,
     }
   }
 
+  /// Keeps the symbols written for each file in `written_symbols`, such as
+  /// to know what was listed before watching for changes.
+  ///
+  /// Symbols are kept before the filter, if any, is applied.
+  pub fn keeping_written_symbols(mut self, written_symbols: &'static WrittenSymbols) -> Self {
+    self.written_symbols = written_symbols.some();
+
+    self
+  }
+
   pub fn into_writer(self) -> W {
     self.writer
   }
@@ -59,6 +90,10 @@ impl<W: Write> Worker<W> {
       return ().ok();
     }
 
+    if self.format.reuse_previous(file_path, file_modified) {
+      return ().ok();
+    }
+
     let Some(cache) = self.cache.take() else {
       let symbol_stream = Parser::new(file_path, language, self.config).symbol_stream().await?;
       self.emit_symbols(file_path, symbol_stream).await?;
@@ -66,36 +101,91 @@ impl<W: Write> Worker<W> {
       return ().ok();
     };
 
-    if cache.is_file_cached(file_path, file_modified).await? {
-      let symbol_stream = cache.get_symbols(file_path).filter_ok();
-      let result = self.emit_symbols(file_path, symbol_stream).await;
-      self.cache = Some(cache);
+    let fingerprint = self.config.fingerprint(language).unwrap_or_default();
+    let content = match cache.lookup_file(file_path, file_modified, fingerprint).await? {
+      lookup @ (Lookup::Hit | Lookup::Copied) => {
+        self.cache = Some(cache);
 
-      return result;
-    }
-    let symbol_stream = Parser::new(file_path, language, self.config).symbol_stream().await?;
-    self.cache_and_emit_symbols(&cache, file_path, file_modified, symbol_stream).await?;
+        self.has_copied_hits |= matches!(lookup, Lookup::Copied);
+        self.cache_hits.push(file_task.file_path);
+        if self.cache_hits.len() >= Self::CACHE_HITS_BATCH_SIZE {
+          self.emit_cache_hits().await?;
+        }
+
+        return ().ok();
+      }
+      Lookup::Miss(content) => content,
+    };
+    let Content { bytes, hash } = match content {
+      Some(content) => content,
+      None => Content::read(file_path).await?,
+    };
+    let symbol_stream = Parser::new(file_path, language, self.config)
+      .symbol_stream_from_bytes(bytes)
+      .await?;
+    let result = if self.config.is_partial() {
+      self.emit_symbols(file_path, symbol_stream).await
+    } else {
+      self
+        .cache_and_emit_symbols(&cache, file_path, file_modified, fingerprint, hash, symbol_stream)
+        .await
+    };
 
     self.cache = Some(cache);
 
-    ().ok()
+    result
   }
 
   pub async fn emit_symbols(&mut self, file_path: &Path, symbol_stream: impl Stream<Item = Symbol>) -> Result<()> {
     let stream = symbol_stream.unique_symbols();
+    let mut symbols = Vec::new();
     futures::pin_mut!(stream);
     while let Some(symbol) = stream.next().await {
       self.write_symbol(file_path, &symbol)?;
+
+      if self.written_symbols.is_some() {
+        symbols.push(symbol);
+      }
     }
 
+    self.keep_written_symbols(file_path, symbols);
+
     ().ok()
   }
 
+  /// Writes the cached symbols of the pending cache hits.
+  async fn emit_cache_hits(&mut self) -> Result<()> {
+    let Some(cache) = self.cache.take() else {
+      return ().ok();
+    };
+    let file_paths = std::mem::take(&mut self.cache_hits);
+
+    let result = async {
+      if std::mem::take(&mut self.has_copied_hits) {
+        cache.flush().await?;
+      }
+
+      let mut symbols = cache.get_symbols_of_files(&file_paths).await?;
+      for file_path in &file_paths {
+        let symbol_stream = futures::stream::iter(symbols.remove(file_path).unwrap_or_default());
+        self.emit_symbols(file_path, symbol_stream).await?;
+      }
+
+      ().ok()
+    }
+    .await;
+    self.cache = Some(cache);
+
+    result
+  }
+
   async fn cache_and_emit_symbols(
     &mut self,
     cache: &Cache,
     file_path: &Path,
     file_modified: &DateTime<Utc>,
+    fingerprint: &str,
+    content_hash: i64,
     symbol_stream: impl Stream<Item = Symbol>,
   ) -> Result<()> {
     let symbol_stream = symbol_stream.unique_symbols();
@@ -108,29 +198,44 @@ impl<W: Write> Worker<W> {
       symbols.push(symbol);
     }
 
-    cache.insert_file(file_path, file_modified).await?;
-    cache.insert_symbols(file_path, &symbols).await?;
-    cache.set_file_is_fully_parsed(file_path).await?;
+    if self.written_symbols.is_some() {
+      self.keep_written_symbols(file_path, symbols.clone());
+    }
+
+    cache
+      .write_file(file_path, file_modified, fingerprint, content_hash, symbols)
+      .await?;
 
     ().ok()
   }
 
+  /// Keeps those of `symbols` whose kinds are included by the config, if
+  /// written symbols are kept.
+  fn keep_written_symbols(&self, file_path: &Path, mut symbols: Vec<Symbol>) {
+    let Some(written_symbols) = self.written_symbols else { return };
+
+    symbols.retain(|symbol| self.config.contains_kind(symbol.kind));
+    if !symbols.is_empty() {
+      written_symbols
+        .lock()
+        .expect("poisoned written symbols lock")
+        .insert(file_path.to_path_buf(), symbols)
+        .ignore();
+    }
+  }
+
   pub fn write_symbol(&mut self, file_path: &Path, symbol: &Symbol) -> Result<()> {
-    write!(
-      self.writer,
-      "{lang}{dlm}{kind}{dlm}{path}{dlm}{line}{dlm}{col}{dlm}{lead}{dlm}{text}{dlm}{trail}{end}",
-      lang = symbol.language.colored(self.color),
-      kind = symbol.kind.colored(self.color),
-      path = file_path.display(),
-      line = symbol.line,
-      col = symbol.column,
-      lead = symbol.leading_str(),
-      text = symbol.content,
-      trail = symbol.trailing_str(),
-      dlm = self.delimiter,
-      end = self.separator,
-    )
-    .context("failed to write symbol")
+    if !self.config.contains_kind(symbol.kind) {
+      return ().ok();
+    }
+
+    if let Some(filter) = self.filter
+      && !filter.should_write(file_path, symbol)
+    {
+      return ().ok();
+    }
+
+    self.format.write_symbol(&mut self.writer, file_path, symbol)
   }
 
   pub async fn run(mut self) -> Result<()> {
@@ -139,12 +244,16 @@ impl<W: Write> Worker<W> {
       self.process_file_task(file_task).await?;
     }
 
+    if !self.cache_hits.is_empty() {
+      self.emit_cache_hits().await?;
+    }
+
     ().ok()
   }
 }
 
-#[extend::ext]
-impl<T: Stream<Item = Symbol>> T {
+#[extend::ext(name = UniqueSymbolsExt)]
+pub impl<T: Stream<Item = Symbol>> T {
   fn unique_symbols(self) -> impl Stream<Item = Symbol> {
     let mut symbol_positions = HashSet::<(i64, i64)>::new();
 
@@ -0,0 +1,537 @@
+use std::{
+  collections::HashSet,
+  fs,
+  path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, TimeZone, Utc};
+use cymbal::{
+  cache::{Cache, Content, Lookup},
+  search::SearchTerm,
+  symbol::{Kind, Symbol},
+};
+use futures::StreamExt;
+use sqlx::{Connection, SqliteConnection, sqlite::SqliteConnectOptions};
+
+/// A temporary directory, which is removed when dropped.
+struct TempDir {
+  /// The canonical path of the directory.
+  path: PathBuf,
+  _dir: tempfile::TempDir,
+}
+
+impl TempDir {
+  fn new(name: &str) -> Self {
+    let dir = tempfile::Builder::new()
+      .prefix(&format!("cymbal-test-{name}-"))
+      .tempdir()
+      .expect("failed to create temp dir");
+
+    Self {
+      path: dir.path().canonicalize().expect("failed to canonicalize temp dir"),
+      _dir: dir,
+    }
+  }
+
+  /// Writes a file at `relative_path`, returning its path.
+  fn write(&self, relative_path: &str, content: &str) -> PathBuf {
+    let path = self.path.join(relative_path);
+    fs::create_dir_all(path.parent().unwrap()).expect("failed to create parent dir");
+    fs::write(&path, content).expect("failed to write file");
+
+    path
+  }
+}
+
+fn modified(seconds: i64) -> DateTime<Utc> {
+  Utc.timestamp_opt(seconds, 0).unwrap()
+}
+
+fn function(content: &str) -> Symbol {
+  Symbol::test(Kind::Function, "", content, 1, 4)
+}
+
+async fn symbol_contents(cache: &Cache, file_path: &Path) -> Vec<String> {
+  cache.get_symbols(file_path).map(|symbol| symbol.unwrap().content).collect().await
+}
+
+async fn open(dir: &TempDir) -> Cache {
+  Cache::from_dirpath(&dir.path.join("cache"), &dir.path, false).await.unwrap()
+}
+
+/// Writes `content` as the only symbol of `file_path` and waits for it to be
+/// committed.
+async fn write(cache: &Cache, file_path: &Path, seconds: i64, content: &str) {
+  cache
+    .write_file(file_path, &modified(seconds), "fingerprint", 0, vec![function(content)])
+    .await
+    .unwrap();
+  cache.flush().await.unwrap();
+}
+
+/// Two handles on one cache, as if opened by two processes, alternately write
+/// the same file, and the latest write always wins, regardless of which
+/// handle was opened first.
+async fn check_later_writer_wins(name: &str, first_opened_writes_first: bool) {
+  let dir = TempDir::new(name);
+  let file_path = dir.write("main.rs", "fn main() {}");
+  let first_opened = open(&dir).await;
+  let second_opened = open(&dir).await;
+  let (a, b) = if first_opened_writes_first {
+    (&first_opened, &second_opened)
+  } else {
+    (&second_opened, &first_opened)
+  };
+
+  write(a, &file_path, 1, "a").await;
+  write(b, &file_path, 2, "b").await;
+  assert_eq!(symbol_contents(a, &file_path).await, ["b"]);
+  write(a, &file_path, 3, "c").await;
+
+  let cache = open(&dir).await;
+  assert_eq!(symbol_contents(&cache, &file_path).await, ["c"]);
+  assert!(cache.lookup_file(&file_path, &modified(3), "fingerprint").await.unwrap().is_hit());
+  assert!(!cache.lookup_file(&file_path, &modified(2), "fingerprint").await.unwrap().is_hit());
+  assert!(a.lookup_file(&file_path, &modified(3), "fingerprint").await.unwrap().is_hit());
+}
+
+#[tokio::test]
+async fn later_writer_wins_when_opened_first() {
+  check_later_writer_wins("later-writer-opened-first", false).await;
+}
+
+#[tokio::test]
+async fn later_writer_wins_when_opened_last() {
+  check_later_writer_wins("later-writer-opened-last", true).await;
+}
+
+#[tokio::test]
+async fn stale_cleanup_keeps_files_written_since_walk() {
+  let dir = TempDir::new("stale-cleanup-generation");
+  let walked_path = dir.write("walked.rs", "fn walked() {}");
+  let stale_path = dir.write("stale.rs", "fn stale() {}");
+  let created_path = dir.write("created.rs", "fn created() {}");
+  let walking = open(&dir).await;
+  let other = open(&dir).await;
+
+  write(&walking, &walked_path, 1, "walked").await;
+  write(&walking, &stale_path, 1, "stale").await;
+
+  // another process caches a file after the walk started, which the walk
+  // didn't find
+  let generation = walking.generation().await.unwrap();
+  write(&other, &created_path, 1, "created").await;
+
+  walking
+    .delete_stale_file_paths(&dir.path, &HashSet::from([walked_path.clone()]), generation)
+    .await
+    .unwrap();
+  walking.flush().await.unwrap();
+
+  assert_eq!(symbol_contents(&walking, &walked_path).await, ["walked"]);
+  assert!(symbol_contents(&walking, &stale_path).await.is_empty());
+  assert_eq!(symbol_contents(&walking, &created_path).await, ["created"]);
+}
+
+async fn stats_root(cache: &Cache) -> PathBuf {
+  cache.stats().await.unwrap().root
+}
+
+#[tokio::test]
+async fn root_is_nearest_git_ancestor() {
+  let dir = TempDir::new("git-root");
+  fs::create_dir_all(dir.path.join("project/.git")).unwrap();
+  let file_path = dir.write("project/src/main.rs", "fn main() {}");
+
+  let cache = Cache::from_dirpath(&dir.path.join("cache"), &dir.path.join("project/src"), false)
+    .await
+    .unwrap();
+  assert_eq!(stats_root(&cache).await, dir.path.join("project"));
+
+  // the key is relative to the root, so the same file is found from anywhere
+  // in the project
+  write(&cache, &file_path, 1, "main").await;
+  let sampled_files = cache.sample_files(0).await.unwrap();
+  assert_eq!(sampled_files.len(), 1);
+  assert_eq!(sampled_files[0].0, file_path);
+}
+
+/// The root stored in the database of `cache`, if any.
+async fn stored_root(cache: &Cache) -> Option<PathBuf> {
+  let mut connection = SqliteConnection::connect_with(&SqliteConnectOptions::new().filename(cache.stats().await.unwrap().path))
+    .await
+    .unwrap();
+  let stored_root: Option<Vec<u8>> = sqlx::query_scalar("SELECT value FROM metadata WHERE key = 'root'")
+    .fetch_optional(&mut connection)
+    .await
+    .unwrap();
+  connection.close().await.unwrap();
+
+  stored_root.map(|stored_root| PathBuf::from(String::from_utf8(stored_root).unwrap()))
+}
+
+#[tokio::test]
+async fn root_is_stored_root_or_cache_parent() {
+  let dir = TempDir::new("stored-root");
+  fs::create_dir_all(dir.path.join("src")).unwrap();
+  let cache_dir_path = dir.path.join("cache");
+
+  // the root is only stored when the cache is written to
+  let cache = Cache::from_dirpath(&cache_dir_path, &dir.path, false).await.unwrap();
+  assert_eq!(stats_root(&cache).await, dir.path);
+  assert_eq!(stored_root(&cache).await, None);
+  cache.set_root().await.unwrap();
+  assert_eq!(stored_root(&cache).await, Some(dir.path.clone()));
+
+  // a later run from a subdirectory keeps the stored root
+  let cache = Cache::from_dirpath(&cache_dir_path, &dir.path.join("src"), false).await.unwrap();
+  assert_eq!(stats_root(&cache).await, dir.path);
+
+  // but not from outside of it, where the cache's parent is the root
+  let elsewhere = TempDir::new("stored-root-elsewhere");
+  let cache = Cache::from_dirpath(&cache_dir_path, &elsewhere.path, false).await.unwrap();
+  assert_eq!(stats_root(&cache).await, dir.path);
+}
+
+#[tokio::test]
+async fn keys_resolve_symlinks_to_root() {
+  let dir = TempDir::new("symlinked-root");
+  let file_path = dir.write("project/src/main.rs", "fn main() {}");
+  let link_path = dir.path.join("link");
+  std::os::unix::fs::symlink(dir.path.join("project"), &link_path).unwrap();
+  let cache_dir_path = dir.path.join("project/cache");
+
+  let linked = Cache::from_dirpath(&cache_dir_path, &link_path, false).await.unwrap();
+  assert_eq!(stats_root(&linked).await, dir.path.join("project"));
+  write(&linked, &link_path.join("src/main.rs"), 1, "main").await;
+
+  // the file is keyed relative to the root rather than by its absolute path
+  let sampled_files = linked.sample_files(0).await.unwrap();
+  assert_eq!(sampled_files.len(), 1);
+  assert_eq!(sampled_files[0].0, file_path);
+
+  let direct = Cache::from_dirpath(&cache_dir_path, &dir.path.join("project"), false)
+    .await
+    .unwrap();
+  assert_eq!(symbol_contents(&direct, &file_path).await, ["main"]);
+  assert!(direct.lookup_file(&file_path, &modified(1), "fingerprint").await.unwrap().is_hit());
+}
+
+/// Files outside of the root are keyed by their absolute path, which must
+/// not be considered under the root, whose key is empty.
+#[tokio::test]
+async fn files_outside_root_are_not_under_root() {
+  let dir = TempDir::new("outside-root");
+  let inside_path = dir.write("project/main.rs", "fn main() {}");
+  let outside_path = dir.write("shared/lib.rs", "fn lib() {}");
+  let project_path = dir.path.join("project");
+  let shared_path = dir.path.join("shared");
+
+  let cache = Cache::from_dirpath(&project_path.join("cache"), &project_path, false)
+    .await
+    .unwrap();
+  write(&cache, &inside_path, 1, "main").await;
+  write(&cache, &outside_path, 1, "lib").await;
+
+  // cleaning up the root leaves files outside of it
+  let generation = cache.generation().await.unwrap();
+  cache
+    .delete_stale_file_paths(&project_path, &HashSet::from([inside_path.clone()]), generation)
+    .await
+    .unwrap();
+  cache.flush().await.unwrap();
+  assert_eq!(symbol_contents(&cache, &outside_path).await, ["lib"]);
+
+  let symbols_under = async |search_paths: &[PathBuf]| -> Vec<(PathBuf, String)> {
+    cache
+      .symbols_under(search_paths, &HashSet::new(), None)
+      .map(|symbol| symbol.map(|(file_path, symbol)| (file_path, symbol.content)).unwrap())
+      .collect()
+      .await
+  };
+
+  assert_eq!(
+    symbols_under(std::slice::from_ref(&project_path)).await,
+    [(inside_path.clone(), "main".to_string())]
+  );
+  assert_eq!(
+    symbols_under(std::slice::from_ref(&shared_path)).await,
+    [(outside_path.clone(), "lib".to_string())]
+  );
+
+  let mut symbols = symbols_under(&[project_path.clone(), shared_path.clone()]).await;
+  symbols.sort();
+  assert_eq!(
+    symbols,
+    [(inside_path.clone(), "main".to_string()), (outside_path.clone(), "lib".to_string())]
+  );
+
+  let search = async |search_paths: &[PathBuf]| -> Vec<PathBuf> {
+    cache
+      .search(search_paths, &SearchTerm::new("lib"), false, &HashSet::new(), None, 10)
+      .map(|symbol| symbol.unwrap().0)
+      .collect()
+      .await
+  };
+
+  assert!(search(std::slice::from_ref(&project_path)).await.is_empty());
+  assert_eq!(
+    search(&[project_path.clone(), shared_path.clone()]).await,
+    std::slice::from_ref(&outside_path)
+  );
+
+  // and cleaning up outside of the root leaves the root's files
+  let generation = cache.generation().await.unwrap();
+  cache
+    .delete_stale_file_paths(&shared_path, &HashSet::new(), generation)
+    .await
+    .unwrap();
+  cache.flush().await.unwrap();
+  assert!(symbol_contents(&cache, &outside_path).await.is_empty());
+  assert_eq!(symbol_contents(&cache, &inside_path).await, ["main"]);
+}
+
+#[tokio::test]
+async fn validated_content_is_reused_or_returned() {
+  let dir = TempDir::new("validate-content");
+  let original_path = dir.write("original.rs", "fn shared() {}");
+  let copy_path = dir.write("copy.rs", "fn shared() {}");
+  let other_path = dir.write("other.rs", "fn other() {}");
+  let cache = Cache::from_dirpath(&dir.path.join("cache"), &dir.path, true).await.unwrap();
+
+  let content = Content::read(&original_path).await.unwrap();
+  cache
+    .write_file(&original_path, &modified(1), "fingerprint", content.hash, vec![function("shared")])
+    .await
+    .unwrap();
+  cache.flush().await.unwrap();
+
+  // a touched file with unchanged content is still cached
+  assert!(
+    cache
+      .lookup_file(&original_path, &modified(2), "fingerprint")
+      .await
+      .unwrap()
+      .is_hit()
+  );
+
+  // an identical file is cached with a copy of the symbols once flushed
+  let lookup = cache.lookup_file(&copy_path, &modified(1), "fingerprint").await.unwrap();
+  assert!(matches!(lookup, Lookup::Copied));
+  cache.flush().await.unwrap();
+  assert_eq!(symbol_contents(&cache, &copy_path).await, ["shared"]);
+  assert!(matches!(
+    cache.lookup_file(&copy_path, &modified(1), "fingerprint").await.unwrap(),
+    Lookup::Hit
+  ));
+
+  // identical files are found after the original is deleted
+  cache.delete_file(&original_path).await.unwrap();
+  cache.flush().await.unwrap();
+  let third_path = dir.write("third.rs", "fn shared() {}");
+  assert!(matches!(
+    cache.lookup_file(&third_path, &modified(1), "fingerprint").await.unwrap(),
+    Lookup::Copied
+  ));
+  cache.flush().await.unwrap();
+  assert_eq!(symbol_contents(&cache, &third_path).await, ["shared"]);
+
+  // but not if another process deleted the identical files, which this one
+  // didn't see
+  let other = Cache::from_dirpath(&dir.path.join("cache"), &dir.path, true).await.unwrap();
+  for file_path in [&copy_path, &third_path] {
+    other.delete_file(file_path).await.unwrap();
+  }
+  other.flush().await.unwrap();
+
+  let fourth_path = dir.write("fourth.rs", "fn shared() {}");
+  assert!(matches!(
+    cache.lookup_file(&fourth_path, &modified(1), "fingerprint").await.unwrap(),
+    Lookup::Copied
+  ));
+  cache.flush().await.unwrap();
+  assert!(symbol_contents(&cache, &fourth_path).await.is_empty());
+  assert!(matches!(
+    cache.lookup_file(&fourth_path, &modified(1), "fingerprint").await.unwrap(),
+    Lookup::Miss(Some(_))
+  ));
+
+  // but not with another fingerprint
+  let Lookup::Miss(Some(content)) = cache.lookup_file(&copy_path, &modified(1), "other").await.unwrap() else {
+    panic!("expected a miss with content");
+  };
+  assert_eq!(content.bytes, b"fn shared() {}");
+
+  // a different file is returned with the content that was read
+  let Lookup::Miss(Some(content)) = cache.lookup_file(&other_path, &modified(1), "fingerprint").await.unwrap() else {
+    panic!("expected a miss with content");
+  };
+  assert_eq!(content.bytes, b"fn other() {}");
+  assert_eq!(content.hash, Cache::content_hash(b"fn other() {}"));
+}
+
+/// Changes are committed by the writer in batches, and once flushed are both
+/// in the database and reflected by the loaded file infos.
+#[tokio::test]
+async fn writes_and_deletes_are_committed_when_flushed() {
+  let dir = TempDir::new("writer");
+  let cache = open(&dir).await;
+  let file_paths: Vec<PathBuf> = (0..300).map(|i| dir.write(&format!("{i}.rs"), "fn f() {}")).collect();
+  let (deleted_paths, kept_paths) = file_paths.split_at(100);
+
+  // the file infos are loaded before writing, so they must be kept in sync
+  assert!(
+    !cache
+      .lookup_file(&file_paths[0], &modified(1), "fingerprint")
+      .await
+      .unwrap()
+      .is_hit()
+  );
+
+  let generation = cache.generation().await.unwrap();
+  for (i, file_path) in file_paths.iter().enumerate() {
+    cache
+      .write_file(file_path, &modified(1), "fingerprint", 0, vec![function(&format!("f{i}"))])
+      .await
+      .unwrap();
+  }
+  for file_path in deleted_paths {
+    cache.delete_file(file_path).await.unwrap();
+  }
+  cache.flush().await.unwrap();
+
+  // 400 changes don't fit in one batch, but don't take one transaction each
+  let batches = cache.generation().await.unwrap() - generation;
+  assert!((2..400).contains(&batches), "{batches} batches");
+
+  let reopened = open(&dir).await;
+  let stats = reopened.stats().await.unwrap();
+  assert_eq!((stats.files, stats.symbols), (200, 200));
+  assert_eq!(symbol_contents(&reopened, &kept_paths[0]).await, ["f100"]);
+
+  for cache in [&cache, &reopened] {
+    assert!(
+      !cache
+        .lookup_file(&deleted_paths[0], &modified(1), "fingerprint")
+        .await
+        .unwrap()
+        .is_hit()
+    );
+    assert!(
+      cache
+        .lookup_file(&kept_paths[0], &modified(1), "fingerprint")
+        .await
+        .unwrap()
+        .is_hit()
+    );
+    assert!(
+      !cache
+        .lookup_file(&kept_paths[0], &modified(2), "fingerprint")
+        .await
+        .unwrap()
+        .is_hit()
+    );
+  }
+}
+
+/// Garbage collection and clearing apply after pending writes, and keep the
+/// file infos in sync.
+#[tokio::test]
+async fn gc_and_clear_apply_after_pending_writes() {
+  let dir = TempDir::new("maintenance");
+  let cache = open(&dir).await;
+  let kept_path = dir.write("kept.rs", "fn kept() {}");
+  let deleted_path = dir.write("deleted.rs", "fn deleted() {}");
+  assert!(!cache.lookup_file(&kept_path, &modified(1), "fingerprint").await.unwrap().is_hit());
+
+  for file_path in [&kept_path, &deleted_path] {
+    cache
+      .write_file(file_path, &modified(1), "fingerprint", 0, vec![function("f")])
+      .await
+      .unwrap();
+  }
+  std::fs::remove_file(&deleted_path).unwrap();
+  assert_eq!(cache.gc().await.unwrap(), 1);
+  assert!(cache.lookup_file(&kept_path, &modified(1), "fingerprint").await.unwrap().is_hit());
+  assert!(
+    !cache
+      .lookup_file(&deleted_path, &modified(1), "fingerprint")
+      .await
+      .unwrap()
+      .is_hit()
+  );
+
+  cache
+    .write_file(&kept_path, &modified(2), "fingerprint", 0, vec![function("f")])
+    .await
+    .unwrap();
+  cache.clear().await.unwrap();
+  let stats = cache.stats().await.unwrap();
+  assert_eq!((stats.files, stats.symbols), (0, 0));
+  assert!(!cache.lookup_file(&kept_path, &modified(2), "fingerprint").await.unwrap().is_hit());
+}
+
+/// A change that fails to commit is reported by the next flush only, and is
+/// not cached, while the rest of its batch is.
+#[tokio::test]
+async fn flush_reports_failed_changes() {
+  let dir = TempDir::new("writer-error");
+  let failing_path = dir.write("failing.rs", "fn failing() {}");
+  let other_path = dir.write("other.rs", "fn other() {}");
+  let cache = open(&dir).await;
+  assert!(
+    !cache
+      .lookup_file(&failing_path, &modified(1), "fingerprint")
+      .await
+      .unwrap()
+      .is_hit()
+  );
+
+  let mut connection = SqliteConnection::connect_with(&SqliteConnectOptions::new().filename(cache.stats().await.unwrap().path))
+    .await
+    .unwrap();
+  sqlx::query("CREATE TRIGGER failing BEFORE INSERT ON symbol WHEN NEW.content = 'failing' BEGIN SELECT RAISE(ABORT, 'failing'); END")
+    .execute(&mut connection)
+    .await
+    .unwrap();
+
+  let generation = cache.generation().await.unwrap();
+  cache
+    .write_file(&failing_path, &modified(1), "fingerprint", 0, vec![function("failing")])
+    .await
+    .unwrap();
+  cache
+    .write_file(&other_path, &modified(1), "fingerprint", 0, vec![function("other")])
+    .await
+    .unwrap();
+  assert!(cache.flush().await.is_err());
+  cache.flush().await.unwrap();
+  assert_eq!(cache.generation().await.unwrap(), generation + 1);
+
+  assert!(
+    !cache
+      .lookup_file(&failing_path, &modified(1), "fingerprint")
+      .await
+      .unwrap()
+      .is_hit()
+  );
+  assert!(symbol_contents(&cache, &failing_path).await.is_empty());
+  assert!(cache.lookup_file(&other_path, &modified(1), "fingerprint").await.unwrap().is_hit());
+  assert_eq!(symbol_contents(&cache, &other_path).await, ["other"]);
+
+  let reopened = open(&dir).await;
+  assert!(
+    !reopened
+      .lookup_file(&failing_path, &modified(1), "fingerprint")
+      .await
+      .unwrap()
+      .is_hit()
+  );
+  assert!(
+    reopened
+      .lookup_file(&other_path, &modified(1), "fingerprint")
+      .await
+      .unwrap()
+      .is_hit()
+  );
+}
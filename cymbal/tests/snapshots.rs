@@ -6,6 +6,7 @@ use std::{
 use cymbal::{
   channel,
   config::{Config, Language},
+  format::Format,
   parser::Parser,
   worker::Worker,
 };
@@ -48,7 +49,13 @@ async fn check(filename: &str) {
 
   let (_tx, rx) = channel::bounded(1);
   let display_path = Path::new("tests/languages").join(filename);
-  let mut worker = Worker::new(None, config, rx, ' ', '\n', true, Vec::<u8>::new());
+  let format = Format::Text {
+    delimiter: ' ',
+    separator: '\n',
+    color: true,
+    ranges: false,
+  };
+  let mut worker = Worker::new(None, config, rx, format, None, Vec::<u8>::new());
   worker.emit_symbols(&display_path, symbol_stream).await.unwrap();
   let snapshot = String::from_utf8(worker.into_writer()).unwrap();
 